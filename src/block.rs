@@ -0,0 +1,39 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub trait BlockDevice: Send + Sync {
+    fn block_num(&self) -> usize;
+    // read the block `block_id` into `buf`, which is BLOCK_SIZE bytes long
+    fn read_block(&self, block_id: usize, buf: &mut [u8]);
+    // write `buf`, which is BLOCK_SIZE bytes long, to the block `block_id`
+    fn write_block(&self, block_id: usize, buf: &[u8]);
+}
+
+// Block device kept in kernel heap memory, e.g. as a swap file for tests
+pub struct RamDisk {
+    data: Mutex<Vec<u8>>
+}
+
+impl RamDisk {
+    pub fn new(block_num: usize) -> Self {
+        let mut data = Vec::new();
+        data.resize(block_num * BLOCK_SIZE, 0);
+        RamDisk { data: Mutex::new(data) }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_num(&self) -> usize {
+        self.data.lock().len() / BLOCK_SIZE
+    }
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+        let start = block_id * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data.lock()[start..(start + BLOCK_SIZE)]);
+    }
+    fn write_block(&self, block_id: usize, buf: &[u8]) {
+        let start = block_id * BLOCK_SIZE;
+        self.data.lock()[start..(start + BLOCK_SIZE)].copy_from_slice(buf);
+    }
+}
@@ -1,6 +1,7 @@
     .section .text.entry
     .global _start
 _start:
+    # a0 = hartid, a1 = dtb physical address, both kept for rust_main
     # set page table item for kernel
     lui     t0, %hi(boot_page_table_sv39)
     li      t1, 0xffffffffc0000000 - 0x80000000
@@ -11,9 +12,13 @@ _start:
     or      t0, t0, t1
     csrw    satp, t0
     sfence.vma
+    # tp holds the hart id while in the kernel
+    mv      tp, a0
     # set kernel stack
     # la      sp, bootstacktop
     lui     sp, %hi(bootstacktop)
+    # the outermost frame, stack walking stops here
+    mv      s0, zero
     # call rust_main
     lui     t0, %hi(rust_main)
     addi    t0, t0, %lo(rust_main)
@@ -21,17 +26,35 @@ _start:
 
     .section .bss.stack
     .align 12
+    # left unmapped, so that overflowing the boot stack faults
+    .global bootstackguard
+bootstackguard:
+    .space 4096
     .global bootstack
 bootstack:
     .space 4096 * 4
     .global bootstacktop
 bootstacktop:
+    # traps move here when the trap frame does not fit on the kernel stack
+    .global trapstack
+trapstack:
+    .space 4096 * 2
+    .global trapstacktop
+trapstacktop:
 
     .section .data
     .align 12
 boot_page_table_sv39:
+    .zero 8 * 258
+    # map 0xffffffc080000000 to 0x80000000 (16GB), the beginning of RAM in the direct map
+    # VRWAD
+    .set ppn, 0x80000
+    .rept 16
+    .quad (ppn << 10) | 0xc7
+    .set ppn, ppn + 0x40000
+    .endr
+    .zero 8 * (511 - 258 - 16)
     # map 0xffffffffc0000000 to 0x80000000 (1GB)
-    .zero 8 * 511
     # VRWXAD
     # .quad (0x80000 << 10) | 0xcf 
     .quad 0x200000cf  
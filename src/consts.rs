@@ -1,9 +1,29 @@
-pub const PHYSICAL_MEMORY_END: usize = 0x88000000;
 pub const KERNEL_BEGIN_PADDR: usize = 0x80200000;
 pub const KERNEL_BEGIN_VADDR: usize = 0xffffffffc0200000;
+// the kernel image is at virtual address pa + KERNEL_IMAGE_OFFSET
+pub const KERNEL_IMAGE_OFFSET: usize = KERNEL_BEGIN_VADDR - KERNEL_BEGIN_PADDR;
+// the direct map of physical memory, see memory/addr.rs
+pub const PHYSICAL_MEMORY_OFFSET: usize = 0xffffffc000000000;
 
-pub const MAX_PHYSICAL_MEMORY: usize = 0x8000000;
-pub const MAX_PHYSICAL_PAGES: usize = MAX_PHYSICAL_MEMORY >> 12;
+pub const PAGE_SIZE: usize = 4096;
+
+// physical memory from this address is out of reach of the direct map (128GiB)
+pub const MAX_PHYSICAL_ADDR: usize = 0x2000000000;
+
+// kernel virtual memory handed out by vmalloc, right after the direct map
+pub const VMALLOC_START: usize = 0xffffffe000000000;
+pub const VMALLOC_END: usize = 0xffffffff00000000;
+
+// kernel stacks from vmalloc, the boot stack in boot/entry64.asm has the same size
+pub const KERNEL_STACK_SIZE: usize = 0x4000;
 
 pub const KERNEL_HEAP_SIZE: usize = 0x800000;
 
+// the heap grows by at least this many bytes of contiguous frames
+pub const KERNEL_HEAP_GROW_SIZE: usize = 0x200000;
+
+// regions the heap can be made of, the first one and those it grows by
+pub const KERNEL_HEAP_REGIONS: usize = 64;
+
+// live heap allocations the leak tracker keeps the call sites of
+pub const LEAK_TRACK_CAPACITY: usize = 0x1000;
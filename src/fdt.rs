@@ -0,0 +1,186 @@
+// Minimal flattened device tree (DTB) parser
+// It only walks the structure block and the memory reservation block,
+// which is all the kernel needs to discover its physical memory.
+use core::slice;
+use core::str;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let b = data.get(off..(off + 4))?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be64(data: &[u8], off: usize) -> Option<u64> {
+    Some(((be32(data, off)? as u64) << 32) | be32(data, off + 4)? as u64)
+}
+
+fn align4(x: usize) -> usize { (x + 3) & !3 }
+
+// null terminated string beginning at `off`
+fn cstr(data: &[u8], off: usize) -> Option<&str> {
+    let rest = data.get(off..)?;
+    let len = rest.iter().position(|&c| c == 0)?;
+    str::from_utf8(&rest[..len]).ok()
+}
+
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    struct_off: usize,
+    strings_off: usize,
+    rsvmap_off: usize
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = be32(data, 4)? as usize;
+        if total_size > data.len() {
+            return None;
+        }
+        let data = &data[..total_size];
+        Some(Fdt {
+            data,
+            struct_off: be32(data, 8)? as usize,
+            strings_off: be32(data, 12)? as usize,
+            rsvmap_off: be32(data, 16)? as usize
+        })
+    }
+
+    // The caller must make sure that a device tree blob is mapped at `addr`
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt<'static>> {
+        let header = slice::from_raw_parts(addr as *const u8, 8);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = be32(header, 4)? as usize;
+        Fdt::new(slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    // entries of the memory reservation block, as (address, size) pairs
+    pub fn reservations(&self) -> Reservations<'a> {
+        Reservations { data: self.data, off: self.rsvmap_off }
+    }
+
+    pub fn tokens(&self) -> Tokens<'a> {
+        Tokens {
+            data: self.data,
+            strings: self.data.get(self.strings_off..).unwrap_or(&[]),
+            off: self.struct_off
+        }
+    }
+}
+
+pub struct Reservations<'a> {
+    data: &'a [u8],
+    off: usize
+}
+
+impl<'a> Iterator for Reservations<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let addr = be64(self.data, self.off)?;
+        let size = be64(self.data, self.off + 8)?;
+        if addr == 0 && size == 0 {
+            return None;
+        }
+        self.off += 16;
+        Some((addr, size))
+    }
+}
+
+pub enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(&'a str, &'a [u8])
+}
+
+pub struct Tokens<'a> {
+    data: &'a [u8],
+    strings: &'a [u8],
+    off: usize
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    // stop at FDT_END as well as on any malformed token
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let tag = be32(self.data, self.off)?;
+            self.off += 4;
+            match tag {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(self.data, self.off)?;
+                    self.off = align4(self.off + name.len() + 1);
+                    return Some(Token::BeginNode(name));
+                }
+                FDT_END_NODE => return Some(Token::EndNode),
+                FDT_PROP => {
+                    let len = be32(self.data, self.off)? as usize;
+                    let name_off = be32(self.data, self.off + 4)? as usize;
+                    let value_off = self.off + 8;
+                    let value = self.data.get(value_off..(value_off + len))?;
+                    let name = cstr(self.strings, name_off)?;
+                    self.off = align4(value_off + len);
+                    return Some(Token::Prop(name, value));
+                }
+                FDT_NOP => continue,
+                _ => return None
+            }
+        }
+    }
+}
+
+// Read a property value made of 32-bit cells as an integer, e.g. #address-cells
+pub fn read_u32(value: &[u8]) -> Option<u32> {
+    be32(value, 0)
+}
+
+// Iterate over the (address, size) pairs of a `reg` property
+pub fn reg(value: &[u8], addr_cells: usize, size_cells: usize) -> Reg<'_> {
+    Reg { value, addr_cells, size_cells }
+}
+
+pub struct Reg<'a> {
+    value: &'a [u8],
+    addr_cells: usize,
+    size_cells: usize
+}
+
+impl<'a> Reg<'a> {
+    fn read_cells(&mut self, cells: usize) -> Option<u64> {
+        let mut res: u64 = 0;
+        for i in 0..cells {
+            res = (res << 32) | be32(self.value, i * 4)? as u64;
+        }
+        self.value = &self.value[(cells * 4)..];
+        Some(res)
+    }
+}
+
+impl<'a> Iterator for Reg<'a> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        let cells = self.addr_cells + self.size_cells;
+        if cells == 0 || self.value.len() < cells * 4 {
+            return None;
+        }
+        let addr = self.read_cells(self.addr_cells)?;
+        let size = self.read_cells(self.size_cells)?;
+        Some((addr, size))
+    }
+}
@@ -1,31 +1,42 @@
-use crate::consts::*;
-
 global_asm!(include_str!("boot/entry64.asm"));
 
+// a0 = hartid and a1 = physical address of the device tree blob 
+// are passed through from the bootloader by _start
 #[no_mangle]
-pub extern "C" fn rust_main() -> ! {
-    extern "C" {
-        fn end(); 
+pub extern "C" fn rust_main(hartid: usize, dtb: usize) -> ! {
+    println!("Hart {} booting with device tree at 0x{:x}", hartid, dtb);
+    let layout = crate::memory::detect_layout(dtb);
+    for region in layout.regions() {
+        println!("free physical memory [0x{:x}, 0x{:x})", region.start, region.end);
     }
-    println!("free physical memory pages = [{:x}, {:x})",
-        ((end as usize - KERNEL_BEGIN_VADDR + KERNEL_BEGIN_PADDR) >> 12) + 1,
-        PHYSICAL_MEMORY_END >> 12
-    );
     crate::interrupt::init();
-    crate::memory::init(
-        ((end as usize - KERNEL_BEGIN_VADDR + KERNEL_BEGIN_PADDR) >> 12) + 1,
-        PHYSICAL_MEMORY_END >> 12
-    );
+    crate::memory::init(&layout);
     frame_allocating_test();
-    crate::memory::init_heap();
+    page_table_test();
+    memory_set_test();
+    lazy_paging_test();
     dynamic_allocating_test();
+    swap_test();
+    kmem_cache_test();
+    vmalloc_test();
+    kernel_stack_test();
+    #[cfg(feature = "stack-overflow-test")]
+    stack_overflow_test();
+    #[cfg(feature = "leak-track")]
+    leak_tracking_test();
+    crate::memory::print_meminfo();
     crate::timer::init();
     loop {}
 }
 
 use crate::memory::{
     alloc_frame, 
-    dealloc_frame
+    dealloc_frame,
+    alloc_contiguous,
+    dealloc_contiguous,
+    frame_data,
+    phys_to_virt,
+    virt_to_phys
 };
 
 fn frame_allocating_test() {
@@ -38,9 +49,126 @@ fn frame_allocating_test() {
     dealloc_frame(f.unwrap());
     println!("alloc {:x?}", alloc_frame());
     println!("alloc {:x?}", alloc_frame());
+    let c = alloc_contiguous(5, 3);
+    println!("alloc 5 contiguous frames aligned to 8: {:x?}", c);
+    assert!(c.unwrap().number() & 7 == 0);
+    dealloc_contiguous(c.unwrap(), 5);
+    println!("dealloc 5 contiguous frames {:x?}", c);
+    // any frame can be reached through the direct map
+    let f = alloc_frame().unwrap();
+    let va = phys_to_virt(f.start_address());
+    println!("frame {:x?} is at {:x?}", f, va);
+    frame_data(f)[0x123] = 0x5a;
+    assert!(unsafe { *((va.as_usize() + 0x123) as *const u8) } == 0x5a);
+    assert!(virt_to_phys(va) == f.start_address());
+    dealloc_frame(f);
     println!("Frame allocating test done.");
 }
 
+fn page_table_test() {
+    use riscv::addr::{VirtAddr, Page};
+    use crate::memory::paging::{PageTable, PageTableFlags, PageSize};
+    println!("In page table test.");
+    let mut pt = PageTable::new().unwrap();
+    let page = Page::of_addr(VirtAddr::new(0x1000_0000));
+    let frame = alloc_frame().unwrap();
+    pt.map(page, frame, PageTableFlags::READABLE | PageTableFlags::WRITABLE).unwrap();
+    let pa = pt.translate(VirtAddr::new(0x1000_0123)).unwrap();
+    println!("0x10000123 is translated to {:x?}", pa);
+    assert!(pa.as_usize() == frame.start_address().as_usize() + 0x123);
+    assert!(pt.map(page, frame, PageTableFlags::READABLE).is_err());
+    pt.update_flags(page, PageTableFlags::READABLE).unwrap();
+    assert!(pt.unmap(page).unwrap() == frame);
+    assert!(pt.translate(VirtAddr::new(0x1000_0123)).is_none());
+    dealloc_frame(frame);
+
+    // a 2MiB page is split when one page of it is unmapped
+    let huge = alloc_contiguous(512, 9).unwrap();
+    let pa = huge.start_address();
+    pt.map_huge(VirtAddr::new(0x4000_0000), pa, PageSize::Size2M, PageTableFlags::READABLE).unwrap();
+    assert!(pt.page_size(VirtAddr::new(0x4010_0000)) == Some(PageSize::Size2M));
+    assert!(pt.translate(VirtAddr::new(0x4010_0123)).unwrap().as_usize() == pa.as_usize() + 0x10_0123);
+    let hole = Page::of_addr(VirtAddr::new(0x4010_0000));
+    assert!(pt.unmap(hole).unwrap().start_address().as_usize() == pa.as_usize() + 0x10_0000);
+    assert!(pt.translate(VirtAddr::new(0x4010_0123)).is_none());
+    assert!(pt.page_size(VirtAddr::new(0x4010_1000)) == Some(PageSize::Size4K));
+    assert!(pt.translate(VirtAddr::new(0x4010_1123)).unwrap().as_usize() == pa.as_usize() + 0x10_1123);
+    pt.unmap_linear(0x4000_0000, 0x4020_0000);
+    assert!(pt.translate(VirtAddr::new(0x4000_0000)).is_none());
+    dealloc_contiguous(huge, 512);
+    println!("Page table test done.");
+}
+
+fn memory_set_test() {
+    use riscv::addr::{VirtAddr, Frame};
+    use crate::memory::paging::PageTableFlags;
+    use crate::memory::memory_set::{MemorySet, AccessType};
+    use crate::memory::memory_set::handler::{ByFrame, Shared};
+    use crate::memory::frame_ref_count;
+    println!("In memory set test.");
+    let rw = PageTableFlags::READABLE | PageTableFlags::WRITABLE;
+    let mut ms = MemorySet::new().unwrap();
+    ms.push(0x1000_0000, 0x1000_4000, rw | PageTableFlags::USER, ByFrame::new()).unwrap();
+    ms.push(0x2000_0000, 0x2000_2000, rw, Shared::new()).unwrap();
+    ms.push(0x1800_0000, 0x1800_1000, rw, ByFrame::new()).unwrap();
+    assert!(ms.push(0x1000_3000, 0x1000_5000, rw, ByFrame::new()).is_err());
+    let by_frame = VirtAddr::new(0x1000_1000);
+    let shared = VirtAddr::new(0x2000_1000);
+    let kernel = VirtAddr::new(0x1800_0000);
+    let pa = ms.page_table().translate(by_frame).unwrap();
+    unsafe { *(phys_to_virt(pa).as_usize() as *mut usize) = 0xdead; }
+    let kernel_pa = ms.page_table().translate(kernel).unwrap();
+    unsafe { *(phys_to_virt(kernel_pa).as_usize() as *mut usize) = 0xbeef; }
+
+    let mut cloned = ms.try_clone().unwrap();
+    assert!(ms.page_table().translate(shared) == cloned.page_table().translate(shared));
+    // kernel pages are copied right away
+    let kernel_copy = cloned.page_table().translate(kernel).unwrap();
+    assert!(kernel_copy != kernel_pa && frame_ref_count(Frame::of_addr(kernel_pa)) == 1);
+    assert!(unsafe { *(phys_to_virt(kernel_copy).as_usize() as *const usize) } == 0xbeef);
+    assert!(ms.page_table().translate(kernel) == Some(kernel_pa));
+    // frames are shared copy-on-write until one of them writes
+    assert!(cloned.page_table().translate(by_frame) == Some(pa));
+    assert!(frame_ref_count(Frame::of_addr(pa)) == 2);
+    assert!(cloned.handle_page_fault(by_frame.as_usize(), AccessType::Write));
+    let copied_pa = cloned.page_table().translate(by_frame).unwrap();
+    println!("copy-on-write page is copied from {:x?} to {:x?}", pa, copied_pa);
+    assert!(copied_pa != pa);
+    assert!(unsafe { *(phys_to_virt(copied_pa).as_usize() as *const usize) } == 0xdead);
+    // the last owner gets the frame back without copying
+    assert!(frame_ref_count(Frame::of_addr(pa)) == 1);
+    assert!(ms.handle_page_fault(by_frame.as_usize(), AccessType::Write));
+    assert!(ms.page_table().translate(by_frame) == Some(pa));
+    drop(cloned);
+
+    ms.remove(0x1000_0000).unwrap();
+    assert!(ms.find_area(0x1000_1000).is_none());
+    println!("Memory set test done.");
+}
+
+fn lazy_paging_test() {
+    use riscv::addr::VirtAddr;
+    use crate::memory::paging::PageTableFlags;
+    use crate::memory::memory_set::handler::Delay;
+    use crate::memory::with_kernel_memory_set;
+    println!("In lazy paging test.");
+    let (start, end) = (0x3000_0000, 0x3100_0000);
+    let rw = PageTableFlags::READABLE | PageTableFlags::WRITABLE;
+    with_kernel_memory_set(|ms| ms.push(start, end, rw, Delay::new())).unwrap();
+    assert!(with_kernel_memory_set(|ms| ms.page_table().translate(VirtAddr::new(start)).is_none()));
+    for addr in (start..end).step_by(0x40_0000) {
+        let p = addr as *mut usize;
+        unsafe {
+            assert!(*p == 0);
+            *p = addr;
+            assert!(*p == addr);
+        }
+    }
+    assert!(with_kernel_memory_set(|ms| ms.page_table().translate(VirtAddr::new(start)).is_some()));
+    with_kernel_memory_set(|ms| ms.remove(start)).unwrap();
+    println!("Lazy paging test done.");
+}
+
 fn dynamic_allocating_test() {
     println!("In dynamic allocating test.");
     use alloc::vec::Vec;
@@ -82,6 +210,204 @@ fn dynamic_allocating_test() {
     let vec_addr = vec.as_ptr() as usize;
     assert!(vec_addr >= lbss && vec_addr < rbss);
     println!("vec is in section .bss!");
+
+    let zeroed: Vec<u64> = alloc::vec![0; 0x800];
+    assert!(zeroed.iter().all(|&x| x == 0));
+    drop(zeroed);
+
+    // running out of memory is an error rather than a panic for fallible allocations
+    let mut too_big: Vec<u8> = Vec::new();
+    assert!(too_big.try_reserve(0x4000_0000).is_err());
+    println!("try_reserve of 1GiB failed as expected");
+
+    // more than the initial heap, the heap has to grow out of .bss
+    let big: Vec<u8> = alloc::vec![0x5a; crate::consts::KERNEL_HEAP_SIZE];
+    let big_addr = big.as_ptr() as usize;
+    println!("big vec is at 0x{:x}", big_addr);
+    assert!(big_addr < lbss || big_addr >= rbss);
+    assert!(big.iter().all(|&b| b == 0x5a));
+    drop(big);
     println!("Dynamic allocating test done.");
 }
 
+fn kmem_cache_test() {
+    use crate::memory::{KmemCache, KernelCache, CacheFrames, print_cache_stats};
+    println!("In kmem cache test.");
+    // a list node packed in 24 bytes and aligned to 8
+    struct Node {
+        value: usize,
+        prev: Option<*mut Node>,
+        next: Option<*mut Node>
+    }
+    fn clear(node: *mut Node) {
+        unsafe { node.write(Node { value: 0, prev: None, next: None }); }
+    }
+    static NODES: KernelCache<Node> = KmemCache::new("node", Some(clear), None, CacheFrames);
+    let mut head: Option<*mut Node> = None;
+    for i in 0..1000 {
+        let node = NODES.alloc().unwrap();
+        unsafe {
+            assert!((*node).value == 0 && (*node).next.is_none());
+            (*node).value = i;
+            (*node).next = head;
+            if let Some(h) = head {
+                (*h).prev = Some(node);
+            }
+        }
+        head = Some(node);
+    }
+    print_cache_stats(&NODES);
+    assert!(NODES.stats().objects_in_use == 1000);
+    assert!(!NODES.destroy());
+    let mut sum = 0;
+    while let Some(node) = head {
+        unsafe {
+            sum += (*node).value;
+            head = (*node).next;
+        }
+        NODES.free(node);
+    }
+    assert!(sum == 999 * 1000 / 2);
+    assert!(NODES.destroy());
+    print_cache_stats(&NODES);
+    println!("Kmem cache test done.");
+}
+
+fn vmalloc_test() {
+    use riscv::addr::VirtAddr;
+    use crate::consts::PAGE_SIZE;
+    use crate::memory::{vmalloc, vfree, with_kernel_memory_set};
+    println!("In vmalloc test.");
+    let translate = |va: usize| with_kernel_memory_set(|ms| ms.page_table().translate(VirtAddr::new(va)));
+    // 4MiB out of frames which need not be contiguous
+    let size = 0x40_0000;
+    let a = vmalloc(size).unwrap();
+    let b = vmalloc(PAGE_SIZE + 1).unwrap();
+    println!("vmalloc 0x{:x} bytes at 0x{:x}, 0x{:x} bytes at 0x{:x}", size, a, PAGE_SIZE + 1, b);
+    let buf = unsafe { core::slice::from_raw_parts_mut(a as *mut usize, size / 8) };
+    assert!(buf.iter().all(|&x| x == 0));
+    for (i, x) in buf.iter_mut().enumerate() {
+        *x = i;
+    }
+    assert!(buf.iter().enumerate().all(|(i, &x)| x == i));
+    // the page below each of them is unmapped
+    assert!(translate(a - PAGE_SIZE).is_none() && translate(b - PAGE_SIZE).is_none());
+    assert!(translate(b + PAGE_SIZE).is_some());
+    vfree(a);
+    assert!(translate(a).is_none());
+    // the space is used again
+    let c = vmalloc(PAGE_SIZE).unwrap();
+    assert!(c < b);
+    assert!(vmalloc(0).is_none() && vmalloc(usize::max_value()).is_none());
+    vfree(b);
+    vfree(c);
+    println!("Vmalloc test done.");
+}
+
+// stack pointer seen by `on_kernel_stack`
+static mut KERNEL_STACK_SP: usize = 0;
+
+extern "C" fn on_kernel_stack() {
+    // a few frames deep, well within the stack
+    fn recurse(n: usize) -> usize {
+        let frame = [n; 64];
+        if n == 0 { 0 } else { recurse(n - 1) + frame[n % 64] }
+    }
+    assert!(recurse(16) == 136);
+    unsafe { asm!("mv $0, sp" : "=r"(KERNEL_STACK_SP) ::: "volatile"); }
+}
+
+fn kernel_stack_test() {
+    use riscv::addr::VirtAddr;
+    use crate::consts::{PAGE_SIZE, KERNEL_STACK_SIZE};
+    use crate::memory::{KernelStack, stack_overflow, with_kernel_memory_set};
+    println!("In kernel stack test.");
+    extern "C" {
+        fn bootstackguard();
+        fn bootstack();
+    }
+    let translate = |va: usize| with_kernel_memory_set(|ms| ms.page_table().translate(VirtAddr::new(va)));
+    // the boot stack has a guard page below it as well
+    assert!(translate(bootstackguard as usize).is_none() && translate(bootstack as usize).is_some());
+    assert!(stack_overflow(bootstack as usize - 8, bootstack as usize - 16) == Some("boot"));
+    assert!(stack_overflow(bootstack as usize + 8, bootstack as usize + 16).is_none());
+    let stack = KernelStack::new().unwrap();
+    println!("kernel stack [0x{:x}, 0x{:x})", stack.bottom(), stack.top());
+    assert!(stack.top() - stack.bottom() == KERNEL_STACK_SIZE);
+    assert!(translate(stack.bottom() - PAGE_SIZE).is_none());
+    stack.run("test", on_kernel_stack);
+    // it ran on the new stack and came back
+    let sp = unsafe { KERNEL_STACK_SP };
+    assert!(sp >= stack.bottom() && sp < stack.top());
+    drop(stack);
+    println!("Kernel stack test done.");
+}
+
+// Overflow a kernel stack on purpose. The fault happens with sp below the stack,
+// so it is only reported if the trap entry moves to the trap stack. Boot ends here.
+#[cfg(feature = "stack-overflow-test")]
+fn stack_overflow_test() {
+    use crate::memory::KernelStack;
+    extern "C" fn overflow() {
+        fn recurse(depth: usize) -> usize {
+            let mut frame = [depth; 32];
+            unsafe { core::ptr::write_volatile(&mut frame[0], depth); }
+            if depth == usize::max_value() { 0 } else { recurse(depth + 1) + frame[depth % 32] }
+        }
+        recurse(0);
+    }
+    println!("In stack overflow test, a kernel stack overflow has to be reported.");
+    let stack = KernelStack::new().unwrap();
+    stack.run("overflow test", overflow);
+    panic!("Stack overflow test: The overflow went unnoticed.");
+}
+
+#[cfg(feature = "leak-track")]
+fn leak_tracking_test() {
+    use alloc::boxed::Box;
+    println!("In leak tracking test.");
+    let mark = crate::memory::leak_mark();
+    let kept = Box::new(0x5a5a_usize);
+    let freed: alloc::vec::Vec<u8> = alloc::vec![0; 100];
+    drop(freed);
+    assert!(crate::memory::print_leaks(mark) == 1);
+    drop(kept);
+    assert!(crate::memory::print_leaks(mark) == 0);
+    println!("Leak tracking test done.");
+}
+
+fn swap_test() {
+    use alloc::boxed::Box;
+    use riscv::addr::VirtAddr;
+    use crate::block::RamDisk;
+    use crate::consts::PAGE_SIZE;
+    use crate::memory::paging::PageTableFlags;
+    use crate::memory::memory_set::{MemorySet, AccessType};
+    use crate::memory::memory_set::handler::Delay;
+    use crate::memory::swap;
+    println!("In swap test.");
+    // 32 pages of swap space
+    swap::init(Box::new(RamDisk::new(0x100)));
+    let urw = PageTableFlags::USER | PageTableFlags::READABLE | PageTableFlags::WRITABLE;
+    let (start, end) = (0x1000_0000, 0x1000_8000);
+    let mut ms = MemorySet::new().unwrap();
+    ms.push(start, end, urw, Delay::new()).unwrap();
+    for va in (start..end).step_by(PAGE_SIZE) {
+        assert!(ms.handle_page_fault(va, AccessType::Write));
+        let pa = ms.page_table().translate(VirtAddr::new(va)).unwrap();
+        unsafe { *(phys_to_virt(pa).as_usize() as *mut usize) = va; }
+    }
+    // the pages are never accessed through the page table, so all of them can be evicted at once
+    assert!(swap::reclaim_pages(8) == 8);
+    for va in (start..end).step_by(PAGE_SIZE) {
+        assert!(ms.page_table().translate(VirtAddr::new(va)).is_none());
+    }
+    for va in (start..end).step_by(PAGE_SIZE) {
+        assert!(ms.handle_page_fault(va, AccessType::Read));
+        let pa = ms.page_table().translate(VirtAddr::new(va)).unwrap();
+        assert!(unsafe { *(phys_to_virt(pa).as_usize() as *const usize) } == va);
+    }
+    assert!(swap::reclaim_pages(4) == 4);
+    drop(ms);
+    println!("Swap test done.");
+}
@@ -1,7 +1,10 @@
 use riscv::register::{
     stvec,
     sscratch,
-    sstatus,
+    sstatus::{
+        self,
+        SPP
+    },
     scause::{
         self,
         Trap,
@@ -10,6 +13,11 @@ use riscv::register::{
     }
 };
 use crate::context::TrapFrame;
+use crate::memory::{
+    AccessType,
+    handle_page_fault,
+    stack_overflow
+};
 use crate::timer::{
     TICKS,
     clock_set_next_event
@@ -36,6 +44,9 @@ pub fn rust_trap(tf: &mut TrapFrame) {
     match cause {
         Trap::Exception(Exception::Breakpoint) => breakpoint(&mut tf.sepc),    
         Trap::Interrupt(Interrupt::SupervisorTimer) => super_timer(),    
+        Trap::Exception(Exception::LoadPageFault) => page_fault(tf, AccessType::Read),
+        Trap::Exception(Exception::StorePageFault) => page_fault(tf, AccessType::Write),
+        Trap::Exception(Exception::InstructionPageFault) => page_fault(tf, AccessType::Execute),
         _ => undefined_trap(tf)
     }
 }
@@ -52,6 +63,24 @@ fn breakpoint(sepc: &mut usize) {
     *sepc += 2;
 }
 
+fn page_fault(tf: &mut TrapFrame, access: AccessType) {
+    // checked first, the overflow may have happened with the kernel address space locked
+    if tf.sstatus.spp() == SPP::Supervisor {
+        if let Some(owner) = stack_overflow(tf.stval, tf.x[2]) {
+            // tp holds the hart id
+            println!("Kernel stack overflow on hart {} ({} stack): sp 0x{:x}, {:?} access to 0x{:x} @0x{:x}",
+                     tf.x[4], owner, tf.x[2], access, tf.stval, tf.sepc);
+            panic!("Kernel stack overflow")
+        }
+    }
+    if !handle_page_fault(tf.stval, access) {
+        // there is no user thread to kill yet, 
+        // so the offender is always the kernel itself
+        println!("Invalid {:?} access to 0x{:x} @0x{:x}", access, tf.stval, tf.sepc);
+        panic!("Unhandled page fault")
+    }
+}
+
 fn super_timer() {
     clock_set_next_event();
     unsafe {
@@ -1,20 +1,49 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(asm)]
 #![feature(global_asm)]
 #![feature(const_fn)]
 #![feature(alloc_error_handler)]
+#![feature(try_reserve)]
+#![cfg_attr(test, feature(test))]
 
 extern crate alloc;
 
+#[cfg(not(test))]
 #[macro_use]
 mod io;
 
 mod consts;
+#[cfg(not(test))]
+mod fdt;
+#[cfg(not(test))]
+mod block;
+#[cfg(not(test))]
 mod init;
+#[cfg(not(test))]
 mod lang_item;
+#[cfg(not(test))]
 mod sbi;
+#[cfg(not(test))]
 mod context;
+#[cfg(not(test))]
 mod interrupt;
+#[cfg(not(test))]
 mod timer;
+#[cfg(not(test))]
 mod memory;
 
+// Only the allocators are built for the host, `make test` runs their tests
+#[cfg(test)]
+mod memory {
+    mod allocator;
+    mod frame_allocator;
+    mod buddy_allocator;
+    mod slub_allocator;
+    mod hybrid_allocator;
+    mod region_allocator;
+    mod tlsf_allocator;
+    mod leak_tracker;
+    mod kmem_cache;
+    mod test_util;
+}
+
@@ -0,0 +1,46 @@
+use riscv::addr::{VirtAddr, PhysAddr, Frame};
+use crate::consts::{
+    KERNEL_BEGIN_VADDR,
+    KERNEL_IMAGE_OFFSET,
+    MAX_PHYSICAL_ADDR,
+    PAGE_SIZE,
+    PHYSICAL_MEMORY_OFFSET
+};
+
+// The kernel half of the Sv39 address space is laid out as
+//   [PHYSICAL_MEMORY_OFFSET, PHYSICAL_MEMORY_OFFSET + MAX_PHYSICAL_ADDR)
+//       the direct map, physical address pa is at pa + PHYSICAL_MEMORY_OFFSET.
+//       The boot page table maps the first 16GiB of RAM in it, the kernel
+//       address space all the memory managed by the frame allocator.
+//   [VMALLOC_START, VMALLOC_END)
+//       virtually contiguous memory from vmalloc, see memory/vmalloc.rs.
+//   [KERNEL_BEGIN_VADDR, end)
+//       the kernel image, at KERNEL_IMAGE_OFFSET from where it is loaded.
+// Frames are only reached through the direct map, the kernel image through its own mapping.
+
+pub fn is_direct_mapped(va: VirtAddr) -> bool {
+    va.as_usize().wrapping_sub(PHYSICAL_MEMORY_OFFSET) < MAX_PHYSICAL_ADDR
+}
+
+// the address of `pa` in the direct map
+pub fn phys_to_virt(pa: PhysAddr) -> VirtAddr {
+    assert!(pa.as_usize() < MAX_PHYSICAL_ADDR, "Memory: 0x{:x} is out of the direct map.", pa.as_usize());
+    VirtAddr::new(pa.as_usize() + PHYSICAL_MEMORY_OFFSET)
+}
+
+// the physical address of an address in the direct map or in the kernel image
+pub fn virt_to_phys(va: VirtAddr) -> PhysAddr {
+    if va.as_usize() >= KERNEL_BEGIN_VADDR {
+        PhysAddr::new(va.as_usize() - KERNEL_IMAGE_OFFSET)
+    } else if is_direct_mapped(va) {
+        PhysAddr::new(va.as_usize() - PHYSICAL_MEMORY_OFFSET)
+    } else {
+        panic!("Memory: 0x{:x} is neither direct mapped nor in the kernel image.", va.as_usize());
+    }
+}
+
+// the content of a frame, through the direct map
+pub fn frame_data(frame: Frame) -> &'static mut [u8; PAGE_SIZE] {
+    let va = phys_to_virt(frame.start_address()).as_usize();
+    unsafe { &mut *(va as *mut [u8; PAGE_SIZE]) }
+}
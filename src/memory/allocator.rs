@@ -1,8 +1,33 @@
+use crate::memory::hybrid_allocator::HeapStats;
+
 pub trait DynamicAllocator {
     fn alloc(&mut self, size: usize, align: usize) -> Option<usize>;
-    fn dealloc(&mut self, addr: usize);
+    // `size` is the one the block was allocated with, or last resized to
+    fn dealloc(&mut self, addr: usize, size: usize);
     fn grained(&self, minsz: usize) -> usize;
-    fn compound_head(&mut self, addr: usize) -> usize;
+    // Resize the block of `size` bytes at `addr` to `new_size` bytes without
+    // moving it, return false if it has to be moved.
+    fn resize_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool;
+    // like `alloc`, but the memory is zeroed
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize>;
+}
+
+// A heap over memory regions which can be added and given back at any time
+pub trait RegionHeap: DynamicAllocator {
+    // Start managing the first region, `zeroed` if the memory is known to be zero
+    fn init(&mut self, start: usize, size: usize, zeroed: bool) {
+        assert!(self.add_region(start, size, false, zeroed));
+    }
+    // Manage [start, start + size) as well, return false if no more regions fit.
+    // A `removable` region can be taken back with `take_unused` once nothing is allocated from it.
+    fn add_region(&mut self, start: usize, size: usize, removable: bool, zeroed: bool) -> bool;
+    // Bytes of a region aligned to the largest power of two dividing them in which
+    // `size` bytes aligned to `align` surely fit, None if there are too many
+    fn region_size_for(&self, size: usize, align: usize) -> Option<usize>;
+    // Remove a removable region nothing is allocated from,
+    // return its address and size so that the memory can be given back
+    fn take_unused(&mut self) -> Option<(usize, usize)>;
+    fn heap_stats(&self) -> HeapStats;
 }
 
 pub fn next_pow_of_2(x: usize) -> usize {
@@ -1,6 +1,9 @@
 // use core::Option;
-use core::cmp::max;
+use core::cmp::{min, max};
 use core::slice;
+use core::ptr;
+use core::mem::size_of;
+use core::marker::Send;
 use crate::memory::allocator::{
     DynamicAllocator,
     prev_pow_of_2,
@@ -9,21 +12,64 @@ use crate::memory::allocator::{
 
 pub const LOG_BUDDY_ALLOCATOR_GRANULARITY: usize = 12;
 pub const BUDDY_ALLOCATOR_GRANULARITY: usize = (1 << LOG_BUDDY_ALLOCATOR_GRANULARITY);
+// blocks of order k hold BUDDY_ALLOCATOR_GRANULARITY << k bytes
+pub const BUDDY_MAX_ORDER: usize = 32;
 
+// The first page of a block is tagged with its order, or'ed with FREE if it is free.
+// The other pages of a block are tagged INSIDE.
+const FREE: u8 = 0x80;
+const INSIDE: u8 = 0xff;
+
+#[derive(Clone, Copy, Default)]
+pub struct BuddyStats {
+    // number of maximal free blocks of each order
+    pub free_blocks: [usize; BUDDY_MAX_ORDER],
+    pub free_bytes: usize,
+    pub largest_free: usize
+}
+
+impl BuddyStats {
+    pub fn merge(&mut self, other: &BuddyStats) {
+        for (n, m) in self.free_blocks.iter_mut().zip(other.free_blocks.iter()) {
+            *n += m;
+        }
+        self.free_bytes += other.free_bytes;
+        self.largest_free = max(self.largest_free, other.largest_free);
+    }
+}
+
+// Links of a free list, kept in the free block itself
+struct FreeBlock {
+    prev: Option<*mut FreeBlock>,
+    next: Option<*mut FreeBlock>
+}
+
+// Blocks of order k are aligned to their size from `addr_high`, the buddy of
+// a block is found by flipping a bit of its page number. Free blocks are in
+// a list per order, so no operation takes more than a step per order.
 pub struct BuddyAllocator<'a> {
-    nodes: Option<&'a mut [u8]>,
-    leaf_num: usize,
+    // the tag of every page from `addr_high`, see FREE and INSIDE
+    pages: Option<&'a mut [u8]>,
+    page_num: usize,
     addr_high: usize,
-    rounded_size: usize
+    rounded_size: usize,
+    free_lists: [Option<*mut FreeBlock>; BUDDY_MAX_ORDER],
+    // Memory below it has never been handed out. As the right half of
+    // a block is taken when it is split, it moves down slowly.
+    fresh_end: usize
 }
 
+unsafe impl<'a> Send for BuddyAllocator<'a> {}
+
 impl<'a> BuddyAllocator<'a> {
     pub const fn new() -> Self {
         BuddyAllocator {
-            nodes: None,
-            leaf_num: 0,
+            pages: None,
+            page_num: 0,
             addr_high: 0,
-            rounded_size: 0
+            rounded_size: 0,
+            free_lists: [None; BUDDY_MAX_ORDER],
+            fresh_end: 0
         }
     }
 
@@ -31,186 +77,177 @@ impl<'a> BuddyAllocator<'a> {
         self.rounded_size = next_pow_of_2(size) << 1;
         let mask = self.rounded_size - 1;
         self.addr_high = start & (!mask);
-        self.leaf_num = self.rounded_size >> LOG_BUDDY_ALLOCATOR_GRANULARITY;
-        if (size <= max(BUDDY_ALLOCATOR_GRANULARITY, self.leaf_num << 1) + BUDDY_ALLOCATOR_GRANULARITY) {
+        self.page_num = self.rounded_size >> LOG_BUDDY_ALLOCATOR_GRANULARITY;
+        if size <= max(BUDDY_ALLOCATOR_GRANULARITY, self.page_num) + BUDDY_ALLOCATOR_GRANULARITY {
             panic!("The space managered by BuddyAllocator is too small.");
         }
-        let nodes_p = start as *mut u8;
-        let nodes: &'a mut [u8] = unsafe{ slice::from_raw_parts_mut(nodes_p, self.leaf_num << 1) };
-        for i in (1..(self.leaf_num << 1)) { nodes[i] = 0; }
-        for i in (0..self.leaf_num) { 
-            let addr = self.addr_high | (i << LOG_BUDDY_ALLOCATOR_GRANULARITY);
-            nodes[self.leaf_num + i] = 
-                if addr <= (start + (self.leaf_num << 1)) || 
-                    addr > (start + size - BUDDY_ALLOCATOR_GRANULARITY) 
-                { 0 } else { 1 };
-        }
-        for i in (1..self.leaf_num).rev() { 
-            let cl_blk_log = nodes[self.child_l(i)];
-            let cr_blk_log = nodes[self.child_r(i)];
-            if cl_blk_log == self.level(i) && cr_blk_log == self.level(i) {
-                nodes[i] = self.level(i) + 1
-            } else {
-                nodes[i] = max(cl_blk_log, cr_blk_log);
+        assert!(Self::log2(self.page_num) < BUDDY_MAX_ORDER);
+        let pages: &'a mut [u8] = unsafe { slice::from_raw_parts_mut(start as *mut u8, self.page_num) };
+        // pages out of the managed memory, including the ones of the tags, are allocated for good
+        for tag in pages.iter_mut() { *tag = 0; }
+        self.pages = Some(pages);
+        self.free_lists = [None; BUDDY_MAX_ORDER];
+        // free the rest as the largest aligned blocks it can be cut into
+        let mut page = self.page_of(start + self.page_num + BUDDY_ALLOCATOR_GRANULARITY - 1);
+        // the managed memory may go past the pages of the table
+        let end = min(self.page_of(start + size), self.page_num);
+        while page < end {
+            let order = min(page.trailing_zeros() as usize, Self::log2(end - page));
+            for inside in page + 1..page + (1 << order) {
+                self.set_tag(inside, INSIDE);
             }
+            self.push(page, order);
+            page += 1 << order;
         }
-        // println!("    nodes: {:?}", nodes);
-        self.nodes = Some(nodes);
+        self.fresh_end = 0;
     }
 
-    pub fn dealloc(&mut self, addr: usize) {
-        if addr & (BUDDY_ALLOCATOR_GRANULARITY - 1) != 0 {
-            panic!("Invalid addr to dealloc.");
-        }
-        let id = self.allocated_node_id(addr);
-        // dealloc
-        let nodes: &mut [u8] = self.nodes.take().unwrap();
-        {
-            let addr = self.node_addr(id);
-            let size = self.node_total_blk(id) << LOG_BUDDY_ALLOCATOR_GRANULARITY;
-            //println!("Buddy allocator: dealloc memory at 0x{:x} with size 0x{:x}.", addr, size);
+    pub fn stats(&self) -> BuddyStats {
+        let mut stats = BuddyStats::default();
+        for (order, list) in self.free_lists.iter().enumerate() {
+            let mut block = *list;
+            while let Some(b) = block {
+                stats.free_blocks[order] += 1;
+                stats.free_bytes += BUDDY_ALLOCATOR_GRANULARITY << order;
+                stats.largest_free = max(stats.largest_free, BUDDY_ALLOCATOR_GRANULARITY << order);
+                block = unsafe { (*b).next };
+            }
         }
-        nodes[id] = 1 + self.level(id);
-        self.nodes = Some(nodes);
-        self.update(id);
+        stats
     }
 
-    pub fn grained(&self, minsz: usize) -> usize {
-        next_pow_of_2(minsz)
+    // The managed memory is known to be zero, e.g. in .bss,
+    // so fresh blocks need not be zeroed by alloc_zeroed.
+    pub fn mark_zeroed(&mut self) {
+        self.fresh_end = self.addr_high + self.rounded_size;
     }
 
-    pub fn compound_head(&mut self, addr: usize) -> usize {
-        let id = self.allocated_node_id(addr);
-        self.node_addr(id)
-    }
-
-    fn find_alloc(&mut self, blk_n: usize, align: usize, id: usize) -> Option<usize> {
-        let nodes = self.nodes.take().unwrap();
-        // println!("    in find_alloc with id: {}, blk_n: {}, node_value: {}", id, blk_n, nodes[id]);
-        if nodes[id] == 0 || (1 << (nodes[id] - 1)) < blk_n {
-            self.nodes = Some(nodes);
-            return None;
-        }
-        self.nodes = Some(nodes);
-        if id >= self.leaf_num && self.node_addr(id) & (next_pow_of_2(align) - 1) == 0 {
-            return Some(id);
-        }
-        let res_r = self.find_alloc(blk_n, align, self.child_r(id));
-        if let Some(res) = res_r {
-            return Some(res);
-        }
-        let res_l = self.find_alloc(blk_n, align, self.child_l(id));
-        if let Some(res) = res_l {
-            return Some(res);
-        }
-        let nodes = self.nodes.take().unwrap();
-        if nodes[id] == self.level(id) + 1 && 
-            self.node_addr(id) & (next_pow_of_2(align) - 1) == 0 {
-            self.nodes = Some(nodes);
-            return Some(id);
-        }
-        self.nodes = Some(nodes);
-        None
-    }
-
-    fn do_alloc(&mut self, id: usize) {
-        let nodes = self.nodes.take().unwrap();
-        nodes[id] = 0;
-        self.nodes = Some(nodes);
-        self.update(id);
-    }
-    
-    fn update(&mut self, idx: usize) {
-        let nodes = self.nodes.take().unwrap();
-        let mut t = idx;
-        while t > 0 {
-            let p = self.parent(t);
-            let cl_blk_log = nodes[self.child_l(p)];
-            let cr_blk_log = nodes[self.child_r(p)];
-            if cl_blk_log == cr_blk_log && cl_blk_log == self.level(p) {
-                nodes[p] = self.level(p) + 1;
-            } else {
-                nodes[p] = max(cl_blk_log, cr_blk_log);
+    fn log2(x: usize) -> usize {
+        prev_pow_of_2(x).trailing_zeros() as usize
+    }
+    // the order of the smallest block holding `size` bytes
+    fn order_of(size: usize) -> usize {
+        if size <= BUDDY_ALLOCATOR_GRANULARITY {
+            0
+        } else {
+            Self::log2(next_pow_of_2(size)) - LOG_BUDDY_ALLOCATOR_GRANULARITY
+        }
+    }
+    fn page_of(&self, addr: usize) -> usize {
+        (addr - self.addr_high) >> LOG_BUDDY_ALLOCATOR_GRANULARITY
+    }
+    fn addr_of(&self, page: usize) -> usize {
+        self.addr_high + (page << LOG_BUDDY_ALLOCATOR_GRANULARITY)
+    }
+    fn tag(&self, page: usize) -> u8 {
+        self.pages.as_ref().unwrap()[page]
+    }
+    fn set_tag(&mut self, page: usize, tag: u8) {
+        self.pages.as_mut().unwrap()[page] = tag;
+    }
+    fn push(&mut self, page: usize, order: usize) {
+        let block = self.addr_of(page) as *mut FreeBlock;
+        let head = self.free_lists[order];
+        unsafe {
+            block.write(FreeBlock { prev: None, next: head });
+            if let Some(head) = head {
+                (*head).prev = Some(block);
+            }
+        }
+        self.free_lists[order] = Some(block);
+        self.set_tag(page, order as u8 | FREE);
+    }
+    // take a free block out of its list, the caller tags it again
+    fn remove(&mut self, page: usize, order: usize) {
+        let block = self.addr_of(page) as *mut FreeBlock;
+        unsafe {
+            let (prev, next) = ((*block).prev, (*block).next);
+            if let Some(next) = next {
+                (*next).prev = prev;
             }
-            t = p;
-        }
-        self.nodes = Some(nodes);
-    }
-    fn child_l(&self, id: usize) -> usize { id << 1 }
-    fn child_r(&self, id: usize) -> usize { id << 1 | 1 }
-    fn parent(&self, id: usize) -> usize { id >> 1 }
-    fn level(&self, id: usize) -> u8 { 
-        (id.leading_zeros() - self.leaf_num.leading_zeros()) as u8
-    }
-    fn node_addr(&self, id: usize) -> usize {
-        let mut l_leaf_id = id;
-        while l_leaf_id < self.leaf_num { l_leaf_id = self.child_l(l_leaf_id); }
-        let offset = (l_leaf_id - self.leaf_num) << LOG_BUDDY_ALLOCATOR_GRANULARITY;
-        self.addr_high | offset
-    }
-    fn node_total_blk(&self, id: usize) -> usize {
-        1 << (self.level(id) + 1)
-    }
-    fn allocated_node_id(&mut self, addr: usize) -> usize {
-        let nodes: &mut [u8] = self.nodes.take().unwrap();
-        let offset_mask = self.rounded_size - 1;
-        let offset = addr & offset_mask;
-        let mut id = self.leaf_num + (offset >> LOG_BUDDY_ALLOCATOR_GRANULARITY);
-        while nodes[id] != 0 { id = self.parent(id); }
-        self.nodes = Some(nodes);
-        id
+            match prev {
+                Some(prev) => (*prev).next = next,
+                None => self.free_lists[order] = next
+            }
+            // the links are the only bytes of fresh memory which are not zero
+            if (block as usize) < self.fresh_end {
+                ptr::write_bytes(block as *mut u8, 0, size_of::<FreeBlock>());
+            }
+        }
     }
 }
 
 impl<'a> DynamicAllocator for BuddyAllocator<'a> {
-    /*fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
-        self.alloc(size, align)
-    }*/
     fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
-        let find_id = self.find_alloc(
-            (size + BUDDY_ALLOCATOR_GRANULARITY - 1) >> LOG_BUDDY_ALLOCATOR_GRANULARITY,
-            align,
-            1
-        );
-        match find_id {
-            Some(id) => { 
-                self.do_alloc(id); 
-                let addr = self.node_addr(id);
-                {
-                    //println!("Buddy allocator: alloc memory at 0x{:x} with size 0x{:x}.", 
-                    //         addr, self.node_total_blk(id) << LOG_BUDDY_ALLOCATOR_GRANULARITY);
-                }
-                Some(addr) 
-            }
-            None => None
+        // a block is aligned to its size
+        let order = max(Self::order_of(size), Self::order_of(align));
+        let mut k = (order..BUDDY_MAX_ORDER).find(|&k| self.free_lists[k].is_some())?;
+        let mut page = self.page_of(self.free_lists[k].unwrap() as usize);
+        self.remove(page, k);
+        // split it down to the order, keeping the right halves
+        while k > order {
+            k -= 1;
+            self.push(page, k);
+            page += 1 << k;
         }
+        self.set_tag(page, order as u8);
+        let addr = self.addr_of(page);
+        self.fresh_end = min(self.fresh_end, addr);
+        Some(addr)
     }
 
-
-    fn dealloc(&mut self, addr: usize) {
-        if addr & (BUDDY_ALLOCATOR_GRANULARITY - 1) != 0 {
+    fn dealloc(&mut self, addr: usize, _size: usize) {
+        let mut page = self.page_of(addr);
+        let tag = self.tag(page);
+        if addr & (BUDDY_ALLOCATOR_GRANULARITY - 1) != 0 || tag & FREE != 0 {
             panic!("Invalid addr to dealloc.");
         }
-        let id = self.allocated_node_id(addr);
-        // dealloc
-        let nodes: &mut [u8] = self.nodes.take().unwrap();
-        {
-            let addr = self.node_addr(id);
-            let size = self.node_total_blk(id) << LOG_BUDDY_ALLOCATOR_GRANULARITY;
-            //println!("Buddy allocator: dealloc memory at 0x{:x} with size 0x{:x}.", addr, size);
+        // merge it with its free buddies
+        let mut order = tag as usize;
+        while (2 << order) <= self.page_num {
+            let buddy = page ^ (1 << order);
+            if self.tag(buddy) != order as u8 | FREE {
+                break;
+            }
+            self.remove(buddy, order);
+            self.set_tag(max(page, buddy), INSIDE);
+            page = min(page, buddy);
+            order += 1;
         }
-        nodes[id] = 1 + self.level(id);
-        self.nodes = Some(nodes);
-        self.update(id);
+        self.push(page, order);
     }
 
     fn grained(&self, minsz: usize) -> usize {
         next_pow_of_2(minsz)
     }
 
-    fn compound_head(&mut self, addr: usize) -> usize {
-        let id = self.allocated_node_id(addr);
-        self.node_addr(id)
+    // Grow the block over its free right buddies. The block stays
+    // the left most part of the merged one, so it never moves.
+    fn resize_in_place(&mut self, addr: usize, _size: usize, new_size: usize) -> bool {
+        let page = self.page_of(addr);
+        let order = self.tag(page) as usize;
+        let target = Self::order_of(new_size);
+        for k in order..target {
+            if page & (1 << k) != 0 || (2 << k) > self.page_num || self.tag(page + (1 << k)) != k as u8 | FREE {
+                return false;
+            }
+        }
+        for k in order..target {
+            self.remove(page + (1 << k), k);
+            self.set_tag(page + (1 << k), INSIDE);
+        }
+        self.set_tag(page, max(order, target) as u8);
+        true
+    }
+
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize> {
+        let fresh_end = self.fresh_end;
+        let addr = self.alloc(size, align)?;
+        // memory never handed out is still zero
+        if addr + size > fresh_end {
+            unsafe { ptr::write_bytes(addr as *mut u8, 0, size); }
+        }
+        Some(addr)
     }
 }
 
@@ -218,3 +255,188 @@ impl<'a> Default for BuddyAllocator<'a> {
     fn default() -> Self { Self::new() }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::test_util::{Rng, HostMemory, stress};
+
+    impl<'a> BuddyAllocator<'a> {
+        // the address of the block `addr` is in
+        fn compound_head(&self, addr: usize) -> usize {
+            let page = self.page_of(addr);
+            // Blocks are aligned to their size, so the first tag which is not INSIDE
+            // met while clearing the low bits of the page number is the one of its block.
+            let mut head = page;
+            let mut k = 0;
+            while self.tag(head) == INSIDE {
+                k += 1;
+                head = page & !((1 << k) - 1);
+            }
+            self.addr_of(head)
+        }
+        // The blocks cover all the pages, the free ones are in the list of their order
+        // and have no free buddy
+        fn check(&self) {
+            let mut page = 0;
+            let mut free_num = 0;
+            while page < self.page_num {
+                let tag = self.tag(page);
+                assert!(tag != INSIDE, "page {} is not in a block", page);
+                let order = (tag & !FREE) as usize;
+                assert_eq!(page & ((1 << order) - 1), 0);
+                for inside in page + 1..page + (1 << order) {
+                    assert_eq!(self.tag(inside), INSIDE);
+                }
+                if tag & FREE != 0 {
+                    free_num += 1;
+                    let buddy = page ^ (1 << order);
+                    assert!((2 << order) > self.page_num || self.tag(buddy) != tag, "free buddies {} and {}", page, buddy);
+                }
+                page += 1 << order;
+            }
+            let mut listed = 0;
+            for (order, list) in self.free_lists.iter().enumerate() {
+                let mut prev = None;
+                let mut block = *list;
+                while let Some(b) = block {
+                    unsafe {
+                        assert_eq!((*b).prev, prev);
+                        assert_eq!(self.tag(self.page_of(b as usize)), order as u8 | FREE);
+                        prev = block;
+                        block = (*b).next;
+                    }
+                    listed += 1;
+                }
+            }
+            assert_eq!(listed, free_num);
+        }
+    }
+
+    #[test]
+    fn stress_random() {
+        let memory = HostMemory::new(0x100000, 0x100000);
+        let mut a = BuddyAllocator::new();
+        a.init(memory.start(), memory.size());
+        a.mark_zeroed();
+        let end = memory.start() + memory.size();
+        stress(&mut a, &mut Rng::new(1), 20000, 0x10000, memory.start(), end, |a| a.check());
+    }
+
+    #[test]
+    fn resize_over_free_buddy() {
+        let memory = HostMemory::new(0x100000, 0x100000);
+        let mut a = BuddyAllocator::new();
+        a.init(memory.start(), memory.size());
+        let mut blocks = Vec::new();
+        while let Some(addr) = a.alloc(0x4000, 1) {
+            blocks.push(addr);
+        }
+        // a block which is the left half of an order larger one
+        let addr = *blocks.iter().find(|&&b| b % 0x8000 == 0 && blocks.contains(&(b + 0x4000))).unwrap();
+        assert!(!a.resize_in_place(addr, 0x4000, 0x8000));
+        for &b in blocks.iter().filter(|&&b| b != addr) {
+            a.dealloc(b, 0x4000);
+        }
+        assert!(a.resize_in_place(addr, 0x4000, 0x8000));
+        assert_eq!(a.compound_head(addr + 0x7000), addr);
+        a.check();
+        a.dealloc(addr, 0x8000);
+        a.check();
+        assert_eq!(a.stats().free_bytes, a.stats().free_blocks.iter().enumerate()
+                   .map(|(order, n)| n * (BUDDY_ALLOCATOR_GRANULARITY << order)).sum::<usize>());
+    }
+
+    #[test]
+    fn aligned_blocks() {
+        // aligned to its size, so none of it falls out of the tree and the larger blocks exist
+        let memory = HostMemory::new(0x100000, 0x100000);
+        let mut a = BuddyAllocator::new();
+        a.init(memory.start(), memory.size());
+        let mut live = Vec::new();
+        for order in 0..6 {
+            let align = BUDDY_ALLOCATOR_GRANULARITY << order;
+            let addr = a.alloc(0x1000, align).unwrap();
+            assert_eq!(addr % align, 0);
+            assert_eq!(a.compound_head(addr + align - 1), addr);
+            live.push(addr);
+            a.check();
+        }
+        for addr in live {
+            a.dealloc(addr, 0x1000);
+            a.check();
+        }
+    }
+
+    mod benches {
+        extern crate test;
+        use super::*;
+        use self::test::Bencher;
+
+        const HEAP: usize = 0x4000000;
+
+        fn heap(memory: &HostMemory) -> BuddyAllocator<'static> {
+            let mut a = BuddyAllocator::new();
+            a.init(memory.start(), memory.size());
+            a
+        }
+
+        // every other page of the heap is allocated, so no two free pages are buddies
+        fn fragment(a: &mut BuddyAllocator) -> Vec<usize> {
+            let mut pages = Vec::new();
+            while let Some(addr) = a.alloc(BUDDY_ALLOCATOR_GRANULARITY, 1) {
+                pages.push(addr);
+            }
+            let kept = pages.iter().cloned().filter(|addr| addr & BUDDY_ALLOCATOR_GRANULARITY == 0).collect();
+            for &addr in pages.iter().filter(|&&addr| addr & BUDDY_ALLOCATOR_GRANULARITY != 0) {
+                a.dealloc(addr, BUDDY_ALLOCATOR_GRANULARITY);
+            }
+            kept
+        }
+
+        #[bench]
+        fn page_in_fragmented_heap(b: &mut Bencher) {
+            let memory = HostMemory::new(HEAP, HEAP);
+            let mut a = heap(&memory);
+            fragment(&mut a);
+            b.iter(|| {
+                let addr = a.alloc(BUDDY_ALLOCATOR_GRANULARITY, 1).unwrap();
+                a.dealloc(addr, BUDDY_ALLOCATOR_GRANULARITY);
+            });
+        }
+
+        #[bench]
+        fn aligned_in_fragmented_heap(b: &mut Bencher) {
+            let memory = HostMemory::new(HEAP, HEAP);
+            let mut a = heap(&memory);
+            let kept = fragment(&mut a);
+            // leave some room for larger blocks at the end
+            for &addr in kept[kept.len() - 64..].iter() {
+                a.dealloc(addr, BUDDY_ALLOCATOR_GRANULARITY);
+            }
+            b.iter(|| {
+                let addr = a.alloc(BUDDY_ALLOCATOR_GRANULARITY, 0x10000).unwrap();
+                a.dealloc(addr, BUDDY_ALLOCATOR_GRANULARITY);
+            });
+        }
+
+        #[bench]
+        fn random_sizes(b: &mut Bencher) {
+            let memory = HostMemory::new(HEAP, HEAP);
+            let mut a = heap(&memory);
+            let mut rng = Rng::new(19);
+            let mut live = Vec::new();
+            b.iter(|| {
+                if live.len() < 1000 && (live.is_empty() || rng.chance(55)) {
+                    let size = rng.range(1, 0x8000);
+                    if let Some(addr) = a.alloc(size, 1 << rng.range(0, 14)) {
+                        live.push((addr, size));
+                    }
+                } else {
+                    let (addr, size) = live.swap_remove(rng.range(0, live.len()));
+                    a.dealloc(addr, size);
+                }
+            });
+        }
+    }
+}
@@ -1,77 +1,587 @@
-use crate::consts::MAX_PHYSICAL_PAGES;
+use core::slice;
+use core::mem::size_of;
+use core::cmp::{min, max};
 
-pub struct SegmentTreeAllocator {
-    nodes: [u8; MAX_PHYSICAL_PAGES << 1],
+// enough levels for 64^6 pages
+const BITMAP_MAX_LEVELS: usize = 6;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    // managed frames, holes excluded
+    pub total: usize,
+    pub free: usize
+}
+
+// Lengths of the free runs inside the pages covered by a node.
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Node {
+    prefix: u32,  // free pages at the left end
+    suffix: u32,  // free pages at the right end
+    longest: u32  // longest free run
+}
+
+impl Node {
+    const fn used() -> Self { Node { prefix: 0, suffix: 0, longest: 0 } }
+    // the free runs of the 64 pages of a level 0 word
+    fn of_word(word: u64) -> Self {
+        // every step shortens each run of set bits by one
+        let mut longest = 0;
+        let mut w = word;
+        while w != 0 {
+            w &= w << 1;
+            longest += 1;
+        }
+        Node { prefix: (!word).trailing_zeros(), suffix: (!word).leading_zeros(), longest }
+    }
+    // the runs of two neighbouring nodes of `half` pages each
+    fn join(l: Node, r: Node, half: u32) -> Self {
+        Node {
+            prefix: if l.prefix == half { half + r.prefix } else { l.prefix },
+            suffix: if r.suffix == half { half + l.suffix } else { r.suffix },
+            longest: max(max(l.longest, r.longest), l.suffix + r.prefix)
+        }
+    }
+}
+
+// Bit i of level 0 is set if page i is free, bit i of level k + 1 is set if
+// word i of level k is not zero, so a free page is found with a word per level.
+// Above the words of level 0 sits the segment tree of free runs, its leaves
+// cover 64 pages each, so an aligned contiguous run is found in O(log n).
+// The bitmap, the tree and the reference counts live in memory handed to `init`,
+// sized for the pages actually managed.
+pub struct BitmapAllocator {
+    // words of all the levels, level 0 first
+    bitmap: Option<&'static mut [u64]>,
+    // first word and number of words of each level
+    levels: [(usize, usize); BITMAP_MAX_LEVELS],
+    level_num: usize,
+    // segment tree of the free runs, word i of level 0 is leaf `leaf_begin + i`
+    runs: Option<&'static mut [Node]>,
     leaf_begin: usize,
+    // number of owners of each allocated page
+    ref_counts: Option<&'static mut [u16]>,
     usable_num: usize,
-    usable_offset: usize
+    usable_offset: usize,
+    reserved_num: usize,
+    free_num: usize
 }
 
-impl SegmentTreeAllocator {
+impl BitmapAllocator {
+    pub const fn new() -> Self {
+        BitmapAllocator {
+            bitmap: None,
+            levels: [(0, 0); BITMAP_MAX_LEVELS],
+            level_num: 0,
+            runs: None,
+            leaf_begin: 0,
+            ref_counts: None,
+            usable_num: 0,
+            usable_offset: 0,
+            reserved_num: 0,
+            free_num: 0
+        }
+    }
+    // the levels of the bitmap for `page_num` pages and the number of words of all of them
+    fn layout(page_num: usize) -> ([(usize, usize); BITMAP_MAX_LEVELS], usize, usize) {
+        let mut levels = [(0, 0); BITMAP_MAX_LEVELS];
+        let mut level_num = 0;
+        let mut bits = page_num;
+        let mut words = 0;
+        loop {
+            let len = (bits + 63) / 64;
+            levels[level_num] = (words, len);
+            level_num += 1;
+            words += len;
+            if len == 1 {
+                break;
+            }
+            assert!(level_num < BITMAP_MAX_LEVELS, "Frame allocator: Too many pages.");
+            bits = len;
+        }
+        (levels, level_num, words)
+    }
+    // nodes of the tree of free runs over `word_num` words of level 0
+    fn run_nodes(word_num: usize) -> usize {
+        (word_num.next_power_of_two() << 1) - 1
+    }
+    // bytes of memory `init` needs to manage `page_num` pages
+    pub fn meta_size(page_num: usize) -> usize {
+        let (levels, _, words) = BitmapAllocator::layout(page_num);
+        words * size_of::<u64>() + BitmapAllocator::run_nodes(levels[0].1) * size_of::<Node>()
+            + page_num * size_of::<u16>()
+    }
+    fn word(&self, level: usize, i: usize) -> u64 {
+        self.bitmap.as_ref().unwrap()[self.levels[level].0 + i]
+    }
+    fn word_mut(&mut self, level: usize, i: usize) -> &mut u64 {
+        let start = self.levels[level].0;
+        &mut self.bitmap.as_mut().unwrap()[start + i]
+    }
     fn child_l(idx: usize) -> usize { (idx << 1) + 1 }
     fn child_r(idx: usize) -> usize { (idx << 1) + 2 }
-    fn parent(idx: usize) -> usize { if idx == 0 { 0 } else { ((idx - 1) >> 1) } }
-    fn update_parents(&mut self, idx: usize) {
-        let mut t = idx;
-        while t > 0 {
-            let p = SegmentTreeAllocator::parent(t);
-            self.nodes[p] = self.nodes[SegmentTreeAllocator::child_l(p)] & 
-                self.nodes[SegmentTreeAllocator::child_r(p)];
-            t = p;
-        }
-    }
-    // Initialize usable physical pages [l, r)
-    pub fn init(&mut self, l: usize, r: usize) {
+    fn parent(idx: usize) -> usize { if idx == 0 { 0 } else { (idx - 1) >> 1 } }
+    fn run(&self, idx: usize) -> Node {
+        self.runs.as_ref().unwrap()[idx]
+    }
+    // number of pages covered by a node
+    fn node_len(&self, idx: usize) -> usize {
+        let depth = 8 * size_of::<usize>() - 1 - (idx + 1).leading_zeros() as usize;
+        ((self.leaf_begin + 1) * 64) >> depth
+    }
+    // set a node, false if it is unchanged
+    fn set_run(&mut self, idx: usize, node: Node) -> bool {
+        let old = &mut self.runs.as_mut().unwrap()[idx];
+        let changed = *old != node;
+        *old = node;
+        changed
+    }
+    fn pull(&mut self, idx: usize) -> bool {
+        let half = (self.node_len(idx) >> 1) as u32;
+        let l = self.run(BitmapAllocator::child_l(idx));
+        let r = self.run(BitmapAllocator::child_r(idx));
+        self.set_run(idx, Node::join(l, r, half))
+    }
+    // recompute the runs of the level 0 words of pages [l, r),
+    // and their ancestors up to the level where none of them changes
+    fn update_runs(&mut self, l: usize, r: usize) {
+        let (first, last) = (l / 64, (r - 1) / 64);
+        let mut changed = false;
+        for i in first..(last + 1) {
+            let node = Node::of_word(self.word(0, i));
+            changed |= self.set_run(self.leaf_begin + i, node);
+        }
+        let mut lo = self.leaf_begin + first;
+        let mut hi = self.leaf_begin + last;
+        while lo > 0 && changed {
+            lo = BitmapAllocator::parent(lo);
+            hi = BitmapAllocator::parent(hi);
+            changed = false;
+            for p in lo..(hi + 1) { changed |= self.pull(p); }
+        }
+    }
+    fn ref_count_mut(&mut self, pos: usize) -> &mut u16 {
+        &mut self.ref_counts.as_mut().unwrap()[pos]
+    }
+    fn is_free(&self, pos: usize) -> bool {
+        self.word(0, pos / 64) & (1 << (pos % 64)) != 0
+    }
+    // set the bit of page `pos`, the levels above change while a word becomes zero or not
+    fn set_free(&mut self, pos: usize, free: bool) {
+        let mut i = pos;
+        for level in 0..self.level_num {
+            let word = self.word_mut(level, i / 64);
+            let was_zero = *word == 0;
+            if free {
+                *word |= 1 << (i % 64);
+            } else {
+                *word &= !(1 << (i % 64));
+            }
+            if was_zero == (*word == 0) {
+                break;
+            }
+            i /= 64;
+        }
+    }
+    // the left most free page from position `pos`
+    fn next_free(&self, pos: usize) -> Option<usize> {
+        // go up until a word has a bit set after the position, then down to the left most one
+        let mut i = pos;
+        let mut level = 0;
+        loop {
+            if level == self.level_num || i / 64 >= self.levels[level].1 {
+                return None;
+            }
+            let word = self.word(level, i / 64) & (!0 << (i % 64));
+            if word != 0 {
+                i = (i & !63) + word.trailing_zeros() as usize;
+                break;
+            }
+            i = i / 64 + 1;
+            level += 1;
+        }
+        while level > 0 {
+            level -= 1;
+            i = i * 64 + self.word(level, i).trailing_zeros() as usize;
+        }
+        Some(i)
+    }
+    // round up position `pos` so that its page number is a multiple of `align`
+    fn align_up(&self, pos: usize, align: usize) -> usize {
+        let ppn = pos + self.usable_offset;
+        ((ppn + align - 1) & !(align - 1)) - self.usable_offset
+    }
+    // find the left most run of `count` free pages starting at an aligned page
+    // inside node `idx`, which covers the `len` pages beginning at `start`
+    fn find(&self, idx: usize, start: usize, len: usize, count: usize, align: usize) -> Option<usize> {
+        if (self.run(idx).longest as usize) < count {
+            return None;
+        }
+        if len == 64 {
+            // a run inside a word of level 0
+            let word = self.word(0, start / 64);
+            let mask = if count == 64 { !0 } else { (1 << count) - 1 };
+            let mut pos = self.align_up(start, align);
+            while pos + count <= start + 64 {
+                if (word >> (pos - start)) & mask == mask {
+                    return Some(pos);
+                }
+                pos += align;
+            }
+            return None;
+        }
+        let half = len >> 1;
+        let l = BitmapAllocator::child_l(idx);
+        let r = BitmapAllocator::child_r(idx);
+        if let Some(pos) = self.find(l, start, half, count, align) {
+            return Some(pos);
+        }
+        // the free run crossing the middle of this node
+        let mid = start + half;
+        let run_begin = mid - self.run(l).suffix as usize;
+        let run_end = mid + self.run(r).prefix as usize;
+        let pos = self.align_up(run_begin, align);
+        if pos + count <= run_end {
+            return Some(pos);
+        }
+        self.find(r, mid, half, count, align)
+    }
+    // drop a reference to the page at `pos`, true if it became free
+    fn release(&mut self, pos: usize) -> bool {
+        assert!(!self.is_free(pos));
+        let cnt = self.ref_count_mut(pos);
+        assert!(*cnt > 0);
+        *cnt -= 1;
+        let freed = *cnt == 0;
+        if freed {
+            self.set_free(pos, true);
+            self.free_num += 1;
+        }
+        freed
+    }
+    // Initialize usable physical pages [l, r), the bookkeeping is kept
+    // in the `meta_size(r - l)` bytes at virtual address `meta`
+    pub fn init(&mut self, l: usize, r: usize, meta: usize) {
         assert!(r > l);
         self.usable_offset = l;
         self.usable_num = r - l;
-        self.leaf_begin = 1;
-        while self.leaf_begin < self.usable_num {
-            self.leaf_begin = self.leaf_begin << 1;
-        }
-        self.leaf_begin -= 1;
-        for i in (0..((self.leaf_begin << 1) + 1)) { self.nodes[i] = 1; }
-        for i in (0..(self.usable_num)) { self.nodes[self.leaf_begin + i] = 0; }
-        for i in (0..self.leaf_begin).rev() { 
-            self.nodes[i] = self.nodes[SegmentTreeAllocator::child_l(i)] & 
-                self.nodes[SegmentTreeAllocator::child_r(i)]; 
-        }
-    }
-    // allocate a physical page from the left most unused page
-    pub fn alloc(&mut self) -> usize {
-        // assume we will not run out of memory
-        if self.nodes[0] == 1 {
-            panic!("Memory: Phisical memory run out!");
-        }
-        let mut p = 1;
-        while p < self.usable_num {
-            p = if self.nodes[SegmentTreeAllocator::child_l(p)] == 0 { 
-                SegmentTreeAllocator::child_l(p) 
-            } else { 
-                SegmentTreeAllocator::child_r(p) 
-            };
-        }
-        let result = p - self.leaf_begin + self.usable_offset;
-        self.nodes[p] = 1;
-        self.update_parents(p);
-        result
-    }
-    // deallocate physical page
+        self.reserved_num = 0;
+        self.free_num = self.usable_num;
+        let (levels, level_num, words) = BitmapAllocator::layout(self.usable_num);
+        self.levels = levels;
+        self.level_num = level_num;
+        let node_num = BitmapAllocator::run_nodes(levels[0].1);
+        self.leaf_begin = node_num >> 1;
+        let bitmap = unsafe { slice::from_raw_parts_mut(meta as *mut u64, words) };
+        let runs_start = meta + words * size_of::<u64>();
+        let runs = unsafe { slice::from_raw_parts_mut(runs_start as *mut Node, node_num) };
+        let ref_counts = unsafe {
+            slice::from_raw_parts_mut((runs_start + node_num * size_of::<Node>()) as *mut u16, self.usable_num)
+        };
+        // every page is free, so is every word of the level below
+        let mut bits = self.usable_num;
+        for &(start, len) in levels[..level_num].iter() {
+            for (i, word) in bitmap[start..(start + len)].iter_mut().enumerate() {
+                let n = min(bits - i * 64, 64);
+                *word = if n == 64 { !0 } else { (1 << n) - 1 };
+            }
+            bits = len;
+        }
+        for cnt in ref_counts.iter_mut() { *cnt = 0; }
+        // leaves past the last word cover no page
+        for node in runs.iter_mut() { *node = Node::used(); }
+        self.bitmap = Some(bitmap);
+        self.runs = Some(runs);
+        self.ref_counts = Some(ref_counts);
+        self.update_runs(0, self.usable_num);
+    }
+    // mark physical pages [l, r) as unusable, e.g. holes between memory regions
+    pub fn reserve(&mut self, l: usize, r: usize) {
+        assert!(l >= self.usable_offset && r <= self.usable_offset + self.usable_num);
+        for pos in (l - self.usable_offset)..(r - self.usable_offset) {
+            assert!(self.is_free(pos));
+            self.set_free(pos, false);
+        }
+        if r > l {
+            self.update_runs(l - self.usable_offset, r - self.usable_offset);
+        }
+        self.reserved_num += r - l;
+        self.free_num -= r - l;
+    }
+    // allocate a physical page from the left most unused page,
+    // return None if all pages are in use
+    pub fn alloc(&mut self) -> Option<usize> {
+        let pos = self.next_free(0)?;
+        self.set_free(pos, false);
+        self.update_runs(pos, pos + 1);
+        *self.ref_count_mut(pos) = 1;
+        self.free_num -= 1;
+        Some(pos + self.usable_offset)
+    }
+    // drop a reference to a physical page, deallocate it when it is the last one
     pub fn dealloc(&mut self, idx: usize) {
-        let mut p = idx - self.usable_offset + self.leaf_begin;
-        assert!(self.nodes[p] == 1);
-        self.nodes[p] = 0;
-        self.update_parents(p);
+        let pos = idx - self.usable_offset;
+        if self.release(pos) {
+            self.update_runs(pos, pos + 1);
+        }
+    }
+    // add an owner to an allocated physical page, return false if it has
+    // as many owners as the reference count holds
+    pub fn share(&mut self, idx: usize) -> bool {
+        let pos = idx - self.usable_offset;
+        assert!(!self.is_free(pos) && self.ref_count(idx) > 0);
+        let count = self.ref_count_mut(pos);
+        match count.checked_add(1) {
+            Some(n) => { *count = n; true }
+            None => false
+        }
+    }
+    pub fn ref_count(&self, idx: usize) -> usize {
+        self.ref_counts.as_ref().unwrap()[idx - self.usable_offset] as usize
+    }
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.usable_num - self.reserved_num,
+            free: self.free_num
+        }
+    }
+    // allocate `count` contiguous physical pages, the first of which
+    // has a page number aligned to 2^align_log2
+    pub fn alloc_contiguous(&mut self, count: usize, align_log2: usize) -> Option<usize> {
+        assert!(count > 0);
+        let pos = self.find(0, 0, (self.leaf_begin + 1) * 64, count, 1 << align_log2)?;
+        for i in pos..(pos + count) {
+            self.set_free(i, false);
+            *self.ref_count_mut(i) = 1;
+        }
+        self.update_runs(pos, pos + count);
+        self.free_num -= count;
+        Some(pos + self.usable_offset)
+    }
+    // drop a reference to each of `count` contiguous physical pages beginning at page `idx`
+    pub fn dealloc_contiguous(&mut self, idx: usize, count: usize) {
+        assert!(count > 0);
+        let pos = idx - self.usable_offset;
+        let mut freed = false;
+        for i in pos..(pos + count) {
+            freed |= self.release(i);
+        }
+        if freed {
+            self.update_runs(pos, pos + count);
+        }
     }
 }
 
 use spin::Mutex;
 
-pub static SEGMENT_TREE_ALLOCATOR: Mutex<SegmentTreeAllocator> 
-    = Mutex::new(SegmentTreeAllocator {
-        nodes: [0; MAX_PHYSICAL_PAGES << 1],
-        leaf_begin: 0,
-        usable_num: 0,
-        usable_offset: 0
-    });
+pub static BITMAP_ALLOCATOR: Mutex<BitmapAllocator> = Mutex::new(BitmapAllocator::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::memory::test_util::Rng;
+
+    const OFFSET: usize = 0x80123;
+    const PAGES: usize = 3000;
+
+    // memory for the bookkeeping of `page_num` pages, filled with garbage
+    fn meta(page_num: usize) -> Vec<u64> {
+        vec![0x5a5a_5a5a_5a5a_5a5a; (BitmapAllocator::meta_size(page_num) + 7) / 8]
+    }
+
+    // Reference model: the owners of each page, `None` for reserved pages
+    struct Model {
+        refs: Vec<Option<u16>>
+    }
+
+    impl Model {
+        fn is_free(&self, pos: usize) -> bool {
+            self.refs[pos] == Some(0)
+        }
+        fn longest_run(&self) -> usize {
+            let (mut run, mut longest) = (0, 0);
+            for pos in 0..PAGES {
+                run = if self.is_free(pos) { run + 1 } else { 0 };
+                longest = max(longest, run);
+            }
+            longest
+        }
+        fn first_run(&self, count: usize, align: usize) -> Option<usize> {
+            (0..PAGES).filter(|&pos| (pos + OFFSET) % align == 0)
+                .find(|&pos| pos + count <= PAGES && (pos..(pos + count)).all(|p| self.is_free(p)))
+        }
+    }
+
+    impl BitmapAllocator {
+        fn check(&self, model: &Model) {
+            for pos in 0..PAGES {
+                assert_eq!(self.is_free(pos), model.is_free(pos), "page {}", pos);
+                assert_eq!(self.ref_counts.as_ref().unwrap()[pos], model.refs[pos].unwrap_or(0));
+            }
+            // a bit of a level is set if the word below is not zero, other bits are clear
+            let mut bits = PAGES;
+            for level in 0..self.level_num {
+                for i in 0..(self.levels[level].1 * 64) {
+                    let set = self.word(level, i / 64) & (1 << (i % 64)) != 0;
+                    let expected = i < bits && (level == 0 && model.is_free(i) || level > 0 && self.word(level - 1, i) != 0);
+                    assert_eq!(set, expected, "bit {} of level {}", i, level);
+                }
+                bits = self.levels[level].1;
+            }
+            // the leaves hold the runs of their words, the other nodes those of their children
+            for idx in (0..(self.leaf_begin * 2 + 1)).rev() {
+                let node = self.run(idx);
+                let expected = if idx >= self.leaf_begin {
+                    let i = idx - self.leaf_begin;
+                    if i < self.levels[0].1 { Node::of_word(self.word(0, i)) } else { Node::used() }
+                } else {
+                    Node::join(self.run(BitmapAllocator::child_l(idx)), self.run(BitmapAllocator::child_r(idx)),
+                               (self.node_len(idx) >> 1) as u32)
+                };
+                assert_eq!(node, expected, "node {}", idx);
+            }
+            assert_eq!(self.run(0).longest as usize, model.longest_run());
+            assert_eq!(self.free_num, (0..PAGES).filter(|&pos| model.is_free(pos)).count());
+        }
+    }
+
+    #[test]
+    fn stress_against_model() {
+        let mut a = BitmapAllocator::new();
+        let mut meta = meta(PAGES);
+        let mut rng = Rng::new(0x5eed);
+        let mut model = Model { refs: vec![Some(0); PAGES] };
+        a.init(OFFSET, OFFSET + PAGES, meta.as_mut_ptr() as usize);
+        a.reserve(OFFSET + 100, OFFSET + 150);
+        for pos in 100..150 { model.refs[pos] = None; }
+        a.check(&model);
+        // references held by the test, as (first page, page count)
+        let mut owned: Vec<(usize, usize)> = Vec::new();
+        for _ in 0..20000 {
+            match rng.range(0, 10) {
+                0..=2 => {
+                    let res = a.alloc();
+                    assert_eq!(res, model.first_run(1, 1).map(|pos| pos + OFFSET));
+                    if let Some(ppn) = res {
+                        model.refs[ppn - OFFSET] = Some(1);
+                        owned.push((ppn, 1));
+                    }
+                }
+                3..=5 => {
+                    let count = rng.range(1, 17);
+                    let align_log2 = rng.range(0, 5);
+                    let res = a.alloc_contiguous(count, align_log2);
+                    assert_eq!(res, model.first_run(count, 1 << align_log2).map(|pos| pos + OFFSET));
+                    if let Some(ppn) = res {
+                        for pos in (ppn - OFFSET)..(ppn - OFFSET + count) { model.refs[pos] = Some(1); }
+                        owned.push((ppn, count));
+                    }
+                }
+                6 if !owned.is_empty() => {
+                    let (ppn, count) = owned[rng.range(0, owned.len())];
+                    let page = rng.range(ppn, ppn + count);
+                    assert!(a.share(page));
+                    *model.refs[page - OFFSET].as_mut().unwrap() += 1;
+                    owned.push((page, 1));
+                    assert_eq!(a.ref_count(page), model.refs[page - OFFSET].unwrap() as usize);
+                }
+                _ if !owned.is_empty() => {
+                    let (ppn, count) = owned.swap_remove(rng.range(0, owned.len()));
+                    if count == 1 { a.dealloc(ppn); } else { a.dealloc_contiguous(ppn, count); }
+                    for pos in (ppn - OFFSET)..(ppn - OFFSET + count) {
+                        *model.refs[pos].as_mut().unwrap() -= 1;
+                    }
+                }
+                _ => {}
+            }
+            a.check(&model);
+        }
+    }
+
+    #[test]
+    fn manages_gigabytes() {
+        // 16GiB from 2GiB
+        let pages = 0x400000;
+        let offset = 0x80000;
+        let mut a = BitmapAllocator::new();
+        let mut meta = meta(pages);
+        assert!(meta.len() * 8 < pages * 3);
+        a.init(offset, offset + pages, meta.as_mut_ptr() as usize);
+        a.reserve(offset, offset + 0x10);
+        assert_eq!(a.alloc(), Some(offset + 0x10));
+        // a gigabyte aligned gigabyte
+        let huge = a.alloc_contiguous(0x40000, 18).unwrap();
+        assert_eq!(huge, 0xc0000);
+        a.reserve(offset + pages - 1, offset + pages);
+        assert_eq!(a.alloc_contiguous(0x40000, 18), Some(0x100000));
+        assert_eq!(a.alloc_contiguous(0x80000, 18), Some(0x140000));
+        assert_eq!(a.alloc_contiguous(0x80000, 18), Some(0x1c0000));
+        assert_eq!(a.alloc_contiguous(0x80000, 18), Some(0x240000));
+        assert_eq!(a.alloc_contiguous(0x80000, 18), Some(0x2c0000));
+        assert_eq!(a.alloc_contiguous(0x80000, 18), Some(0x340000));
+        assert_eq!(a.alloc_contiguous(0x80000, 18), Some(0x3c0000));
+        // the last page is reserved
+        assert_eq!(a.alloc_contiguous(0x40000, 18), None);
+        assert_eq!(a.stats().free, (0xc0000 - 0x80011) + (0x40000 - 1));
+        a.dealloc_contiguous(huge, 0x40000);
+        // the run before it joins the freed gigabyte
+        assert_eq!(a.alloc_contiguous(0x40000, 0), Some(offset + 0x11));
+    }
+
+    #[test]
+    fn share_saturates() {
+        let mut a = BitmapAllocator::new();
+        let mut meta = meta(16);
+        a.init(0x100, 0x110, meta.as_mut_ptr() as usize);
+        let page = a.alloc().unwrap();
+        for _ in 1..0xffff {
+            assert!(a.share(page));
+        }
+        assert!(!a.share(page));
+        assert_eq!(a.ref_count(page), 0xffff);
+        a.dealloc(page);
+        assert_eq!(a.ref_count(page), 0xfffe);
+    }
+
+    mod benches {
+        extern crate test;
+        use super::*;
+        use self::test::Bencher;
+
+        // 16GiB from 2GiB
+        const OFFSET: usize = 0x80000;
+        const PAGES: usize = 0x400000;
+
+        // every other page is in use except for the last `free` pages
+        fn fragmented(meta: &mut Vec<u64>, free: usize) -> BitmapAllocator {
+            let mut a = BitmapAllocator::new();
+            a.init(OFFSET, OFFSET + PAGES, meta.as_mut_ptr() as usize);
+            a.alloc_contiguous(PAGES, 0).unwrap();
+            for ppn in OFFSET..(OFFSET + PAGES) {
+                if ppn % 2 == 1 || ppn >= OFFSET + PAGES - free { a.dealloc(ppn); }
+            }
+            a
+        }
 
+        #[bench]
+        fn page_in_fragmented_memory(b: &mut Bencher) {
+            let mut meta = meta(PAGES);
+            let mut a = fragmented(&mut meta, 0);
+            b.iter(|| {
+                let ppn = a.alloc().unwrap();
+                a.dealloc(ppn);
+            });
+        }
+
+        #[bench]
+        fn contiguous_in_fragmented_memory(b: &mut Bencher) {
+            let mut meta = meta(PAGES);
+            let mut a = fragmented(&mut meta, 0x400);
+            // 2MiB, as for a huge page
+            b.iter(|| {
+                let ppn = a.alloc_contiguous(0x200, 9).unwrap();
+                a.dealloc_contiguous(ppn, 0x200);
+            });
+        }
+    }
+}
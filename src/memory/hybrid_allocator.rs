@@ -1,30 +1,32 @@
 use core::marker::Send;
-use crate::memory::allocator::DynamicAllocator;
-use crate::memory::buddy_allocator::BuddyAllocator;
-use crate::memory::slub_allocator::SlubAllocator;
+use crate::memory::allocator::{DynamicAllocator, RegionHeap};
+use crate::memory::region_allocator::{RegionAllocator, RegionStats};
+use crate::memory::slub_allocator::{SlubAllocator, SlubPoolStats, SLUB_POOL_NUM};
 
-pub struct HybridAllocator<'a> {
-    back: BuddyAllocator<'a>,
-    front: Option<SlubAllocator<BuddyAllocator<'a>>>
+#[derive(Clone, Copy, Default)]
+pub struct HeapStats {
+    pub back: RegionStats,
+    pub slub: [SlubPoolStats; SLUB_POOL_NUM]
 }
 
-unsafe impl<'a> Send for HybridAllocator<'a> {}
+pub struct HybridAllocator {
+    back: RegionAllocator,
+    front: Option<SlubAllocator<RegionAllocator>>
+}
+
+unsafe impl Send for HybridAllocator {}
 
-impl<'a> HybridAllocator<'a> {
+impl HybridAllocator {
     pub const fn new() -> Self {
-        let back = BuddyAllocator::<'a>::new();
+        let back = RegionAllocator::new();
         HybridAllocator {
             back,
             front: None
         }
     }
-    pub fn init(&mut self, start: usize, size: usize) {
-        self.back.init(start, size);
-        self.front = Some(SlubAllocator::<BuddyAllocator<'a>>::new(&mut self.back as *mut BuddyAllocator<'a>));
-    }
 }
 
-impl<'a> DynamicAllocator for HybridAllocator<'a> {
+impl DynamicAllocator for HybridAllocator {
     fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
         if let Some(ref mut f) = self.front {
             f.alloc(size, align)
@@ -32,11 +34,11 @@ impl<'a> DynamicAllocator for HybridAllocator<'a> {
             self.back.alloc(size, align)
         }
     }
-    fn dealloc(&mut self, addr: usize) {
+    fn dealloc(&mut self, addr: usize, size: usize) {
         if let Some(ref mut f) = self.front {
-            f.dealloc(addr);
+            f.dealloc(addr, size);
         } else {
-            self.back.dealloc(addr);
+            self.back.dealloc(addr, size);
         }
     }
     fn grained(&self, minsz: usize) -> usize {
@@ -46,12 +48,96 @@ impl<'a> DynamicAllocator for HybridAllocator<'a> {
             self.back.grained(minsz)
         }
     }
-    fn compound_head(&mut self, addr: usize) -> usize {
+    fn resize_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        if let Some(ref mut f) = self.front {
+            f.resize_in_place(addr, size, new_size)
+        } else {
+            self.back.resize_in_place(addr, size, new_size)
+        }
+    }
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize> {
         if let Some(ref mut f) = self.front {
-            f.compound_head(addr)
+            f.alloc_zeroed(size, align)
         } else {
-            self.back.compound_head(addr)
+            self.back.alloc_zeroed(size, align)
         }
     }
 }
 
+impl RegionHeap for HybridAllocator {
+    fn init(&mut self, start: usize, size: usize, zeroed: bool) {
+        assert!(self.back.add_region(start, size, false, zeroed));
+        self.front = Some(SlubAllocator::<RegionAllocator>::new(&mut self.back as *mut RegionAllocator));
+    }
+    fn add_region(&mut self, start: usize, size: usize, removable: bool, zeroed: bool) -> bool {
+        self.back.add_region(start, size, removable, zeroed)
+    }
+    fn region_size_for(&self, size: usize, align: usize) -> Option<usize> {
+        self.back.region_size_for(size, align)
+    }
+    fn take_unused(&mut self) -> Option<(usize, usize)> {
+        self.back.take_unused()
+    }
+    fn heap_stats(&self) -> HeapStats {
+        HeapStats {
+            back: self.back.stats(),
+            slub: match self.front {
+                Some(ref f) => f.stats(),
+                None => Default::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::test_util::{Rng, HostMemory, stress};
+
+    #[test]
+    fn stress_random() {
+        let memory = HostMemory::new(0x400000, 0x1000);
+        let mut a = HybridAllocator::new();
+        a.init(memory.start(), memory.size(), true);
+        let end = memory.start() + memory.size();
+        stress(&mut a, &mut Rng::new(4), 20000, 0x3000, memory.start(), end, |_| {});
+        assert!(a.heap_stats().slub.iter().all(|pool| pool.objects_in_use == 0));
+    }
+
+    mod benches {
+        extern crate test;
+        use super::*;
+        use self::test::Bencher;
+
+        const REGION: usize = 0x100000;
+
+        // Free a block of `size` bytes and take it back, in the last of
+        // a few regions, the others are filled up with blocks of all sizes
+        fn free_in_last_region(b: &mut Bencher, size: usize) {
+            let memory: Vec<HostMemory> = (0..8).map(|_| HostMemory::new(REGION, 0x1000)).collect();
+            let mut a = HybridAllocator::new();
+            let mut rng = Rng::new(23);
+            a.init(memory[0].start(), REGION, false);
+            for region in memory[1..].iter() {
+                let size = |rng: &mut Rng| if rng.chance(90) { rng.range(1, 2049) } else { rng.range(2049, 0x8000) };
+                while a.alloc(size(&mut rng), 8).is_some() {}
+                assert!(a.add_region(region.start(), REGION, true, false));
+            }
+            let mut addr = a.alloc(size, 8).unwrap();
+            b.iter(|| {
+                a.dealloc(addr, size);
+                addr = a.alloc(size, 8).unwrap();
+            });
+        }
+
+        #[bench]
+        fn small_free(b: &mut Bencher) {
+            free_in_last_region(b, 100);
+        }
+
+        #[bench]
+        fn large_free(b: &mut Bencher) {
+            free_in_last_region(b, 0x3000);
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use crate::consts::{KERNEL_STACK_SIZE, PAGE_SIZE};
+use crate::memory::{vmalloc, vfree, with_kernel_memory_set};
+
+// The bounds of the kernel stack in use. The trap entry moves to the trap stack
+// when the trap frame would go below the bottom, see trap/trap.asm.
+#[no_mangle]
+static mut KERNEL_STACK_BOTTOM: usize = 0;
+static mut KERNEL_STACK_TOP: usize = 0;
+// who the kernel stack in use belongs to, for the overflow report
+static mut KERNEL_STACK_OWNER: &str = "boot";
+
+// Start keeping track of the boot stack, which has an unmapped guard page below it
+// once the kernel is remapped.
+pub fn init() {
+    extern "C" {
+        fn bootstack();
+        fn bootstacktop();
+    }
+    unsafe {
+        KERNEL_STACK_BOTTOM = bootstack as usize;
+        KERNEL_STACK_TOP = bootstacktop as usize;
+    }
+}
+
+// [bottom, top) of the kernel stack in use, empty before `init`
+#[cfg(feature = "leak-track")]
+pub fn current() -> (usize, usize) {
+    unsafe { (KERNEL_STACK_BOTTOM, KERNEL_STACK_TOP) }
+}
+
+// A kernel stack from vmalloc, with the guard page it leaves below every allocation.
+// The bounds and owner of the stack in use and the trap stack are single globals,
+// they are only right for one hart switching stacks with `run` one at a time.
+pub struct KernelStack {
+    bottom: usize
+}
+
+impl KernelStack {
+    pub fn new() -> Option<Self> {
+        vmalloc(KERNEL_STACK_SIZE).map(|bottom| KernelStack { bottom })
+    }
+    pub fn bottom(&self) -> usize {
+        self.bottom
+    }
+    pub fn top(&self) -> usize {
+        self.bottom + KERNEL_STACK_SIZE
+    }
+    // Run `f` on this stack on behalf of `owner` and switch back.
+    // Like all vmalloc memory the stack is only mapped in the kernel address space.
+    pub fn run(&self, owner: &'static str, f: extern "C" fn()) {
+        assert!(with_kernel_memory_set(|ms| ms.page_table().is_active()),
+                "Memory: Kernel stacks are only mapped in the kernel address space.");
+        unsafe {
+            let (bottom, top, old_owner) = (KERNEL_STACK_BOTTOM, KERNEL_STACK_TOP, KERNEL_STACK_OWNER);
+            KERNEL_STACK_BOTTOM = self.bottom;
+            KERNEL_STACK_TOP = self.top();
+            KERNEL_STACK_OWNER = owner;
+            // the old sp is kept in s1, which `f` saves
+            asm!("mv s1, sp
+                  mv sp, $0
+                  jalr $1
+                  mv sp, s1"
+                 :: "r"(self.top()), "r"(f)
+                 : "s1", "ra", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+                   "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "memory"
+                 : "volatile");
+            KERNEL_STACK_BOTTOM = bottom;
+            KERNEL_STACK_TOP = top;
+            KERNEL_STACK_OWNER = old_owner;
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vfree(self.bottom);
+    }
+}
+
+// The owner of the kernel stack in use if a fault at `va` with stack pointer `sp`
+// is an overflow of it, that is `sp` is below its bottom or `va` in its guard page.
+pub fn stack_overflow(va: usize, sp: usize) -> Option<&'static str> {
+    let bottom = unsafe { KERNEL_STACK_BOTTOM };
+    if bottom == 0 {
+        return None;
+    }
+    if sp < bottom || (va < bottom && va >= bottom - PAGE_SIZE) {
+        Some(unsafe { KERNEL_STACK_OWNER })
+    } else {
+        None
+    }
+}
@@ -0,0 +1,156 @@
+use core::mem::{size_of, align_of};
+use core::marker::Send;
+use spin::Mutex;
+use crate::memory::allocator::DynamicAllocator;
+use crate::memory::slub_allocator::{SlubPool, SlubPoolStats};
+
+struct CacheInner<B: DynamicAllocator> {
+    back: B,
+    // built on the first allocation, when the cache has found its place
+    pool: Option<SlubPool<B>>
+}
+
+unsafe impl<B: DynamicAllocator + Send> Send for CacheInner<B> {}
+
+// A named slub pool for objects of type T, packed by their own size and alignment
+// rather than rounded up to a size class. `ctor` runs on every allocated object
+// and `dtor` on every freed one. The frames of the pool point back to it,
+// so a cache must not be moved once it has handed out an object.
+pub struct KmemCache<T, B: DynamicAllocator> {
+    name: &'static str,
+    ctor: Option<fn(*mut T)>,
+    dtor: Option<fn(*mut T)>,
+    inner: Mutex<CacheInner<B>>
+}
+
+impl<T, B: DynamicAllocator> KmemCache<T, B> {
+    pub const fn new(name: &'static str, ctor: Option<fn(*mut T)>, dtor: Option<fn(*mut T)>, back: B) -> Self {
+        KmemCache {
+            name,
+            ctor,
+            dtor,
+            inner: Mutex::new(CacheInner { back, pool: None })
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    pub fn alloc(&self) -> Option<*mut T> {
+        let obj = {
+            let mut inner = self.inner.lock();
+            let back_p = &mut inner.back as *mut B;
+            let pool = inner.pool.get_or_insert_with(|| {
+                SlubPool::with_layout(back_p, size_of::<T>(), align_of::<T>())
+            });
+            pool.alloc(align_of::<T>())? as *mut T
+        };
+        // the constructor may use the cache as well
+        if let Some(ctor) = self.ctor {
+            ctor(obj);
+        }
+        Some(obj)
+    }
+    pub fn free(&self, obj: *mut T) {
+        if let Some(dtor) = self.dtor {
+            dtor(obj);
+        }
+        let mut inner = self.inner.lock();
+        match inner.pool {
+            Some(ref mut pool) => pool.dealloc(obj as usize),
+            None => panic!("Cache {}: 0x{:x} is not allocated.", self.name, obj as usize)
+        }
+    }
+    // `size` is the one of T, a cache which has never been used is all zero
+    pub fn stats(&self) -> SlubPoolStats {
+        match self.inner.lock().pool {
+            Some(ref pool) => SlubPoolStats { size: size_of::<T>(), ..pool.stats() },
+            None => SlubPoolStats { size: size_of::<T>(), ..Default::default() }
+        }
+    }
+    // Give the frames of an empty cache back, return false if objects are still in use.
+    // The cache can be used again afterwards.
+    pub fn destroy(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if let Some(ref mut pool) = inner.pool {
+            if pool.stats().objects_in_use > 0 {
+                return false;
+            }
+            pool.release();
+        }
+        inner.pool = None;
+        true
+    }
+}
+
+impl<T, B: DynamicAllocator> Drop for KmemCache<T, B> {
+    fn drop(&mut self) {
+        if !self.destroy() {
+            panic!("Cache {}: Dropped with objects in use.", self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::buddy_allocator::BuddyAllocator;
+    use crate::memory::test_util::{Rng, HostMemory};
+
+    // 320 bytes, it would take a block of the 384 byte size class
+    #[repr(align(64))]
+    struct Task {
+        id: usize,
+        regs: [usize; 35]
+    }
+
+    fn task_ctor(task: *mut Task) {
+        unsafe { (*task).id = 7; }
+    }
+
+    fn cache(memory: &HostMemory) -> KmemCache<Task, BuddyAllocator<'static>> {
+        let mut back = BuddyAllocator::new();
+        back.init(memory.start(), memory.size());
+        KmemCache::new("task", Some(task_ctor), None, back)
+    }
+
+    #[test]
+    fn objects_are_packed_and_aligned() {
+        let memory = HostMemory::new(0x100000, 0x100000);
+        let cache = cache(&memory);
+        let mut rng = Rng::new(29);
+        let mut live: Vec<*mut Task> = Vec::new();
+        for round in 0..5000 {
+            if live.is_empty() || rng.chance(60) {
+                let task = cache.alloc().unwrap();
+                assert_eq!(task as usize % 64, 0);
+                unsafe {
+                    assert_eq!((*task).id, 7);
+                    (*task).id = round;
+                    (*task).regs = [round; 35];
+                }
+                live.push(task);
+            } else {
+                let task = live.swap_remove(rng.range(0, live.len()));
+                unsafe { assert!((*task).regs.iter().all(|&r| r == (*task).id)); }
+                cache.free(task);
+            }
+            assert_eq!(cache.stats().objects_in_use, live.len());
+        }
+        // next to each other without padding, but for the redzones in debug mode
+        let mut addrs: Vec<usize> = live.iter().map(|&task| task as usize).collect();
+        addrs.sort();
+        #[cfg(not(feature = "slub-debug"))]
+        assert!(addrs.windows(2).any(|w| w[1] - w[0] == size_of::<Task>()));
+        assert!(!cache.destroy());
+        for task in live {
+            cache.free(task);
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.size, stats.objects_in_use), (size_of::<Task>(), 0));
+        assert!(cache.destroy());
+        assert_eq!(cache.stats().current_frames, 0);
+        // a destroyed cache starts over
+        let task = cache.alloc().unwrap();
+        cache.free(task);
+    }
+}
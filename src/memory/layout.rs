@@ -0,0 +1,158 @@
+use core::cmp::{min, max};
+use crate::fdt::{self, Fdt, Token};
+
+pub const MAX_MEMORY_REGIONS: usize = 16;
+
+// physical address range [start, end)
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub end: usize
+}
+
+// Sorted, non-overlapping set of physical memory regions
+pub struct MemoryLayout {
+    regions: [Region; MAX_MEMORY_REGIONS],
+    len: usize
+}
+
+impl MemoryLayout {
+    pub const fn new() -> Self {
+        MemoryLayout {
+            regions: [Region { start: 0, end: 0 }; MAX_MEMORY_REGIONS],
+            len: 0
+        }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+
+    // add [start, end), merging it with the regions it overlaps or touches
+    pub fn add(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let mut merged = Region { start, end };
+        let mut res = MemoryLayout::new();
+        let mut inserted = false;
+        for r in self.regions() {
+            if r.end < merged.start {
+                res.push(*r);
+            } else if r.start > merged.end {
+                if !inserted {
+                    res.push(merged);
+                    inserted = true;
+                }
+                res.push(*r);
+            } else {
+                merged.start = min(merged.start, r.start);
+                merged.end = max(merged.end, r.end);
+            }
+        }
+        if !inserted {
+            res.push(merged);
+        }
+        *self = res;
+    }
+
+    // remove [start, end), splitting the regions it cuts through
+    pub fn remove(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let mut res = MemoryLayout::new();
+        for r in self.regions() {
+            if r.end <= start || r.start >= end {
+                res.push(*r);
+                continue;
+            }
+            if r.start < start {
+                res.push(Region { start: r.start, end: start });
+            }
+            if r.end > end {
+                res.push(Region { start: end, end: r.end });
+            }
+        }
+        *self = res;
+    }
+
+    fn push(&mut self, r: Region) {
+        assert!(self.len < MAX_MEMORY_REGIONS, "Memory: Too many memory regions.");
+        self.regions[self.len] = r;
+        self.len += 1;
+    }
+
+    // Collect the memory described by the `/memory` nodes,
+    // minus the memory reservation block and the `/reserved-memory` children.
+    pub fn from_fdt(fdt: &Fdt) -> Self {
+        let mut ram = MemoryLayout::new();
+        let mut reserved = MemoryLayout::new();
+        // #address-cells and #size-cells default to 2 and 1
+        let (mut addr_cells, mut size_cells) = (2, 1);
+        let (mut resv_addr_cells, mut resv_size_cells) = (2, 1);
+        let mut depth = 0;
+        let mut is_memory = false;
+        let mut in_reserved = false;
+        let mut node_reg: [Option<&[u8]>; 4] = [None; 4];
+        for token in fdt.tokens() {
+            match token {
+                Token::BeginNode(name) => {
+                    depth += 1;
+                    if depth == 2 {
+                        is_memory = name == "memory" || name.starts_with("memory@");
+                        in_reserved = name == "reserved-memory";
+                    }
+                    if depth < node_reg.len() {
+                        node_reg[depth] = None;
+                    }
+                }
+                Token::Prop(name, value) => {
+                    let cells = fdt::read_u32(value).unwrap_or(0) as usize;
+                    match name {
+                        "#address-cells" if depth == 1 => addr_cells = cells,
+                        "#size-cells" if depth == 1 => size_cells = cells,
+                        "#address-cells" if depth == 2 && in_reserved => resv_addr_cells = cells,
+                        "#size-cells" if depth == 2 && in_reserved => resv_size_cells = cells,
+                        "device_type" if depth == 2 => {
+                            is_memory = is_memory || value.starts_with(b"memory\0");
+                        }
+                        "reg" if depth < node_reg.len() => node_reg[depth] = Some(value),
+                        _ => {}
+                    }
+                }
+                Token::EndNode => {
+                    if depth == 0 {
+                        break;
+                    }
+                    if depth == 2 && is_memory {
+                        if let Some(value) = node_reg[depth] {
+                            for (addr, size) in fdt::reg(value, addr_cells, size_cells) {
+                                ram.add(addr as usize, (addr + size) as usize);
+                            }
+                        }
+                    }
+                    if depth == 3 && in_reserved {
+                        if let Some(value) = node_reg[depth] {
+                            for (addr, size) in fdt::reg(value, resv_addr_cells, resv_size_cells) {
+                                reserved.add(addr as usize, (addr + size) as usize);
+                            }
+                        }
+                    }
+                    if depth == 2 {
+                        is_memory = false;
+                        in_reserved = false;
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        for (addr, size) in fdt.reservations() {
+            reserved.add(addr as usize, (addr + size) as usize);
+        }
+        for r in reserved.regions() {
+            ram.remove(r.start, r.end);
+        }
+        ram
+    }
+}
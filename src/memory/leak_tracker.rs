@@ -0,0 +1,258 @@
+use core::ops::Deref;
+use core::alloc::{GlobalAlloc, Layout};
+use spin::Mutex;
+use crate::consts::LEAK_TRACK_CAPACITY;
+
+// return addresses recorded for an allocation, innermost first
+pub const LEAK_TRACK_DEPTH: usize = 4;
+pub type CallSite = [usize; LEAK_TRACK_DEPTH];
+
+#[derive(Clone, Copy)]
+struct Record {
+    addr: usize,
+    layout: Layout,
+    // allocations are numbered in order, see `mark`
+    seq: usize,
+    site: CallSite
+}
+
+// Live allocations in an open addressing hash table keyed by address,
+// it cannot use the heap it keeps track of.
+struct Table {
+    records: [Option<Record>; LEAK_TRACK_CAPACITY],
+    live: usize,
+    // live allocations which did not fit in the table
+    untracked: usize,
+    seq: usize
+}
+
+impl Table {
+    const fn new() -> Self {
+        Table {
+            records: [None; LEAK_TRACK_CAPACITY],
+            live: 0,
+            untracked: 0,
+            seq: 0
+        }
+    }
+    fn slot(addr: usize) -> usize {
+        (addr >> 3) % LEAK_TRACK_CAPACITY
+    }
+    fn insert(&mut self, addr: usize, layout: Layout, site: CallSite) {
+        self.seq += 1;
+        if self.live == LEAK_TRACK_CAPACITY {
+            self.untracked += 1;
+            return;
+        }
+        let mut i = Self::slot(addr);
+        while self.records[i].is_some() {
+            i = (i + 1) % LEAK_TRACK_CAPACITY;
+        }
+        self.records[i] = Some(Record { addr, layout, seq: self.seq, site });
+        self.live += 1;
+    }
+    fn remove(&mut self, addr: usize) {
+        let mut i = Self::slot(addr);
+        loop {
+            match self.records[i] {
+                Some(r) if r.addr == addr => break,
+                Some(_) => i = (i + 1) % LEAK_TRACK_CAPACITY,
+                None => {
+                    // allocated while the table was full
+                    assert!(self.untracked > 0, "Heap: 0x{:x} is not allocated.", addr);
+                    self.untracked -= 1;
+                    return;
+                }
+            }
+        }
+        self.records[i] = None;
+        self.live -= 1;
+        // move the records after the hole back, so that probing does not stop early
+        let mut j = i;
+        loop {
+            j = (j + 1) % LEAK_TRACK_CAPACITY;
+            let r = match self.records[j] {
+                Some(r) => r,
+                None => break
+            };
+            let k = Self::slot(r.addr);
+            let movable = if i <= j { k <= i || k > j } else { k <= i && k > j };
+            if movable {
+                self.records[i] = Some(r);
+                self.records[j] = None;
+                i = j;
+            }
+        }
+    }
+}
+
+// Record the return addresses on the stack by following the frame pointers
+#[cfg(target_arch = "riscv64")]
+#[inline(always)]
+fn call_site() -> CallSite {
+    let mut site = [0; LEAK_TRACK_DEPTH];
+    let mut fp: usize;
+    unsafe { asm!("mv $0, s0" : "=r"(fp) ::: "volatile"); }
+    let (bottom, top) = crate::memory::current_kernel_stack();
+    for ra in site.iter_mut() {
+        // the outermost frame and the trap entry have a zero frame pointer,
+        // anything off the kernel stack in use is not a frame either
+        if fp % 8 != 0 || fp < bottom + 16 || fp > top {
+            break;
+        }
+        unsafe {
+            *ra = *((fp - 8) as *const usize);
+            fp = *((fp - 16) as *const usize);
+        }
+    }
+    site
+}
+
+#[cfg(not(target_arch = "riscv64"))]
+fn call_site() -> CallSite {
+    [0; LEAK_TRACK_DEPTH]
+}
+
+// Keep track of the live allocations of another allocator with the call sites they
+// were allocated at, so that the memory a subsystem forgot to free can be found.
+pub struct LeakTracker<A: GlobalAlloc> {
+    inner: A,
+    table: Mutex<Table>
+}
+
+impl<A: GlobalAlloc> LeakTracker<A> {
+    pub const fn new(inner: A) -> Self {
+        LeakTracker { inner, table: Mutex::new(Table::new()) }
+    }
+    // Allocations made after this call have a larger number than the returned one
+    pub fn mark(&self) -> usize {
+        self.table.lock().seq
+    }
+    // number of live allocations, including the untracked ones
+    pub fn live(&self) -> usize {
+        let table = self.table.lock();
+        table.live + table.untracked
+    }
+    pub fn untracked(&self) -> usize {
+        self.table.lock().untracked
+    }
+    // Call `f(site, count, bytes)` for every call site with live allocations made after `mark`.
+    // The table is locked meanwhile, `f` must not allocate.
+    pub fn for_each_site<F: FnMut(&CallSite, usize, usize)>(&self, mark: usize, mut f: F) {
+        let table = self.table.lock();
+        let since = |r: &Option<Record>| r.filter(|r| r.seq > mark);
+        for (i, r) in table.records.iter().enumerate() {
+            let r = match since(r) {
+                Some(r) => r,
+                None => continue
+            };
+            // the first record of a site reports all of them
+            if table.records[..i].iter().any(|o| since(o).map_or(false, |o| o.site == r.site)) {
+                continue;
+            }
+            let (mut count, mut bytes) = (0, 0);
+            for o in table.records[i..].iter() {
+                if let Some(o) = since(o).filter(|o| o.site == r.site) {
+                    count += 1;
+                    bytes += o.layout.size();
+                }
+            }
+            f(&r.site, count, bytes);
+        }
+    }
+}
+
+impl<A: GlobalAlloc> Deref for LeakTracker<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for LeakTracker<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let site = call_site();
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            self.table.lock().insert(ptr as usize, layout, site);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.table.lock().remove(ptr as usize);
+        self.inner.dealloc(ptr, layout);
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let site = call_site();
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            self.table.lock().insert(ptr as usize, layout, site);
+        }
+        ptr
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let site = call_site();
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            let mut table = self.table.lock();
+            table.remove(ptr as usize);
+            table.insert(new_ptr as usize, Layout::from_size_align_unchecked(new_size, layout.align()), site);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+    use crate::memory::test_util::Rng;
+
+    fn sites(t: &LeakTracker<System>, mark: usize) -> (usize, usize, usize) {
+        let (mut sites, mut count, mut bytes) = (0, 0, 0);
+        t.for_each_site(mark, |_, c, b| {
+            sites += 1;
+            count += c;
+            bytes += b;
+        });
+        (sites, count, bytes)
+    }
+
+    #[test]
+    fn live_allocations_are_reported() {
+        let t = LeakTracker::new(System);
+        let mut rng = Rng::new(17);
+        let mut live = Vec::new();
+        // until there are more live allocations than the table holds
+        while live.len() <= LEAK_TRACK_CAPACITY {
+            if live.is_empty() || rng.chance(60) {
+                let layout = Layout::from_size_align(rng.range(1, 256), 8).unwrap();
+                live.push((unsafe { t.alloc(layout) }, layout));
+            } else {
+                let (ptr, layout) = live.swap_remove(rng.range(0, live.len()));
+                unsafe { t.dealloc(ptr, layout) };
+            }
+            assert_eq!(t.live(), live.len());
+        }
+        assert!(t.untracked() > 0);
+        let (_, count, _) = sites(&t, 0);
+        assert_eq!(count + t.untracked(), live.len());
+        for (ptr, layout) in live.drain(..) {
+            unsafe { t.dealloc(ptr, layout) };
+        }
+        assert_eq!((t.live(), t.untracked()), (0, 0));
+        let mark = t.mark();
+        let old = unsafe { t.alloc(Layout::from_size_align(8, 8).unwrap()) };
+        let mark2 = t.mark();
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = unsafe { t.realloc(t.alloc(layout), layout, 100) };
+        assert_eq!(sites(&t, mark), (1, 2, 108));
+        assert_eq!(sites(&t, mark2), (1, 1, 100));
+        unsafe {
+            t.dealloc(old, Layout::from_size_align(8, 8).unwrap());
+            t.dealloc(ptr, Layout::from_size_align(100, 8).unwrap());
+        }
+        assert_eq!(sites(&t, 0), (0, 0, 0));
+    }
+}
@@ -0,0 +1,45 @@
+use alloc::boxed::Box;
+use crate::consts::PAGE_SIZE;
+use crate::memory::paging::{PageTable, PageTableFlags, PagingError};
+use super::handler::MemoryHandler;
+
+// Virtual memory [start, end) with the same permissions and the same kind of backing
+pub struct MemoryArea {
+    start: usize,
+    end: usize,
+    flags: PageTableFlags,
+    handler: Box<dyn MemoryHandler>
+}
+
+impl MemoryArea {
+    pub fn new(start: usize, end: usize, flags: PageTableFlags, handler: Box<dyn MemoryHandler>) -> Self {
+        MemoryArea {
+            start: start & !(PAGE_SIZE - 1),
+            end: (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+            flags,
+            handler
+        }
+    }
+    pub fn start(&self) -> usize { self.start }
+    pub fn end(&self) -> usize { self.end }
+    pub fn flags(&self) -> PageTableFlags { self.flags }
+    pub fn handler(&self) -> &dyn MemoryHandler { &*self.handler }
+    pub fn contains(&self, va: usize) -> bool {
+        va >= self.start && va < self.end
+    }
+    pub fn is_overlap_with(&self, start: usize, end: usize) -> bool {
+        start < self.end && end > self.start
+    }
+    // map all pages, nothing is left mapped if one of them fails
+    pub fn map(&self, pt: &mut PageTable) -> Result<(), PagingError> {
+        self.handler.map_range(pt, self.start, self.end, self.flags)
+    }
+    pub fn unmap(&self, pt: &mut PageTable) {
+        self.handler.unmap_range(pt, self.start, self.end);
+    }
+    // map all pages into `pt` as a copy of this area in `src`
+    pub fn clone_map(&self, pt: &mut PageTable, src: &mut PageTable) -> Result<MemoryArea, PagingError> {
+        self.handler.clone_map_range(pt, src, self.start, self.end, self.flags)?;
+        Ok(MemoryArea::new(self.start, self.end, self.flags, self.handler.clone()))
+    }
+}
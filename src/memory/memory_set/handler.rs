@@ -0,0 +1,359 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use riscv::addr::{
+    VirtAddr,
+    PhysAddr,
+    Page,
+    Frame
+};
+use crate::consts::PAGE_SIZE;
+use crate::memory::paging::{PageTable, PageTableFlags, PagingError};
+use crate::memory::swap;
+use crate::memory::{alloc_frame, dealloc_frame, share_frame, frame_ref_count, frame_data};
+
+// Decides how the pages of a memory area are backed by frames
+pub trait MemoryHandler: Send + 'static {
+    fn box_clone(&self) -> Box<dyn MemoryHandler>;
+    // map the page at `va` when the area is added to an address space
+    fn map(&self, pt: &mut PageTable, va: usize, flags: PageTableFlags) -> Result<(), PagingError>;
+    // unmap the page at `va` and release its frame if it is owned by the area
+    fn unmap(&self, pt: &mut PageTable, va: usize);
+    // map the page at `va` in `pt` with the same content as in `src`,
+    // `src` may be changed as well, e.g. to share the frame copy-on-write
+    fn clone_map(&self, pt: &mut PageTable, src: &mut PageTable, va: usize, flags: PageTableFlags) 
+        -> Result<(), PagingError>;
+    // resolve a page fault at `va` with an access allowed by `flags`,
+    // return false if the fault is not caused by lazy mapping
+    fn handle_page_fault(&self, _pt: &mut PageTable, _va: usize, _flags: PageTableFlags) -> bool {
+        false
+    }
+    // Map all pages of [start, end), undoing the work already done if one of them fails.
+    // Handlers able to use huge pages map the whole range at once.
+    fn map_range(&self, pt: &mut PageTable, start: usize, end: usize, flags: PageTableFlags)
+        -> Result<(), PagingError> {
+        for va in (start..end).step_by(PAGE_SIZE) {
+            if let Err(e) = self.map(pt, va, flags) {
+                self.unmap_range(pt, start, va);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+    fn unmap_range(&self, pt: &mut PageTable, start: usize, end: usize) {
+        for va in (start..end).step_by(PAGE_SIZE) {
+            self.unmap(pt, va);
+        }
+    }
+    fn clone_map_range(&self, pt: &mut PageTable, src: &mut PageTable, start: usize, end: usize,
+                       flags: PageTableFlags) -> Result<(), PagingError> {
+        for va in (start..end).step_by(PAGE_SIZE) {
+            if let Err(e) = self.clone_map(pt, src, va, flags) {
+                self.unmap_range(pt, start, va);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Clone for Box<dyn MemoryHandler> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+fn page_of(va: usize) -> Page {
+    Page::of_addr(VirtAddr::new(va))
+}
+
+// User pages backed by frames of their own may be swapped out
+fn track_if_user(pt: &PageTable, va: usize, flags: PageTableFlags) {
+    if flags.contains(PageTableFlags::USER) {
+        swap::track(pt, va);
+    }
+}
+
+// Map the page at `va` to `frame`, giving the frame back if it can not be mapped
+fn map_owned(pt: &mut PageTable, va: usize, frame: Frame, flags: PageTableFlags) -> Result<(), PagingError> {
+    let res = pt.map(page_of(va), frame, flags);
+    match res {
+        Ok(()) => track_if_user(pt, va, flags),
+        Err(_) => dealloc_frame(frame)
+    }
+    res
+}
+
+// Unmap a page backed by a frame of its own, which may be swapped out
+fn unmap_owned(pt: &mut PageTable, va: usize) {
+    if swap::free_swapped(pt, va) {
+        return;
+    }
+    if let Ok(frame) = pt.unmap(page_of(va)) {
+        swap::untrack(pt, va);
+        dealloc_frame(frame);
+    }
+}
+
+// Page fault on a page backed by a frame of its own
+fn fault_owned(pt: &mut PageTable, va: usize) -> bool {
+    if swap::is_swapped(pt, va) {
+        swap::swap_in(pt, va)
+    } else {
+        resolve_cow(pt, va)
+    }
+}
+
+// Share the frame of the page at `va` in `src` with `pt`. 
+// Writable pages become read-only copy-on-write pages in both page tables.
+// A frame with too many owners already is copied instead, and so is a kernel page,
+// as the kernel must not fault writing to e.g. a kernel stack.
+fn share_cow(pt: &mut PageTable, src: &mut PageTable, va: usize) -> Result<(), PagingError> {
+    let page = page_of(va);
+    if swap::is_swapped(src, va) && !swap::swap_in(src, va) {
+        return Err(PagingError::NoMemory);
+    }
+    let entry = src.get_entry(page).ok_or(PagingError::NotMapped)?;
+    let frame = entry.frame();
+    let mut flags = entry.flags();
+    if !flags.contains(PageTableFlags::USER) || !share_frame(frame) {
+        let copy = alloc_frame().ok_or(PagingError::NoMemory)?;
+        frame_data(copy).copy_from_slice(frame_data(frame));
+        if let Err(e) = pt.map(page, copy, flags) {
+            dealloc_frame(copy);
+            return Err(e);
+        }
+        track_if_user(pt, va, flags);
+        return Ok(());
+    }
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.insert(PageTableFlags::COPY_ON_WRITE);
+    }
+    if let Err(e) = pt.map(page, frame, flags) {
+        dealloc_frame(frame);
+        return Err(e);
+    }
+    track_if_user(pt, va, flags);
+    src.update_flags(page, flags)
+}
+
+// Resolve a write to a copy-on-write page: the last owner of the frame
+// takes it back as writable, the others get a private copy.
+fn resolve_cow(pt: &mut PageTable, va: usize) -> bool {
+    let page = page_of(va);
+    let entry = match pt.get_entry(page) {
+        Some(entry) => entry,
+        None => return false
+    };
+    let mut flags = entry.flags();
+    if !flags.contains(PageTableFlags::COPY_ON_WRITE) {
+        return false;
+    }
+    flags.remove(PageTableFlags::COPY_ON_WRITE);
+    flags.insert(PageTableFlags::WRITABLE);
+    let frame = entry.frame();
+    if frame_ref_count(frame) == 1 {
+        return pt.update_flags(page, flags).is_ok();
+    }
+    let new_frame = match alloc_frame() {
+        Some(new_frame) => new_frame,
+        None => return false
+    };
+    frame_data(new_frame).copy_from_slice(frame_data(frame));
+    pt.unmap(page).unwrap();
+    pt.map(page, new_frame, flags).unwrap();
+    dealloc_frame(frame);
+    true
+}
+
+// Pages mapped to the physical address `va - offset`, e.g. the kernel image,
+// the physical memory direct map or MMIO registers.
+// The frames are not owned by the area.
+#[derive(Clone)]
+pub struct Linear {
+    offset: usize
+}
+
+impl Linear {
+    pub fn new(offset: usize) -> Self {
+        Linear { offset }
+    }
+}
+
+impl MemoryHandler for Linear {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+    fn map(&self, pt: &mut PageTable, va: usize, flags: PageTableFlags) -> Result<(), PagingError> {
+        pt.map(page_of(va), Frame::of_addr(PhysAddr::new(va - self.offset)), flags)
+    }
+    fn unmap(&self, pt: &mut PageTable, va: usize) {
+        pt.unmap(page_of(va)).unwrap();
+    }
+    fn clone_map(&self, pt: &mut PageTable, _src: &mut PageTable, va: usize, flags: PageTableFlags) 
+        -> Result<(), PagingError> {
+        self.map(pt, va, flags)
+    }
+    // use 2MiB and 1GiB pages where the alignment allows
+    fn map_range(&self, pt: &mut PageTable, start: usize, end: usize, flags: PageTableFlags)
+        -> Result<(), PagingError> {
+        pt.map_linear(start, end, self.offset, flags)
+    }
+    fn unmap_range(&self, pt: &mut PageTable, start: usize, end: usize) {
+        pt.unmap_linear(start, end);
+    }
+    fn clone_map_range(&self, pt: &mut PageTable, _src: &mut PageTable, start: usize, end: usize,
+                       flags: PageTableFlags) -> Result<(), PagingError> {
+        self.map_range(pt, start, end, flags)
+    }
+}
+
+// Every page gets a zeroed frame of its own as soon as the area is mapped.
+// Clones share the frames of user pages copy-on-write. User pages may be swapped out.
+#[derive(Clone)]
+pub struct ByFrame;
+
+impl ByFrame {
+    pub fn new() -> Self {
+        ByFrame
+    }
+}
+
+impl MemoryHandler for ByFrame {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+    fn map(&self, pt: &mut PageTable, va: usize, flags: PageTableFlags) -> Result<(), PagingError> {
+        let frame = alloc_frame().ok_or(PagingError::NoMemory)?;
+        for b in frame_data(frame).iter_mut() { *b = 0; }
+        map_owned(pt, va, frame, flags)
+    }
+    fn unmap(&self, pt: &mut PageTable, va: usize) {
+        unmap_owned(pt, va);
+    }
+    fn clone_map(&self, pt: &mut PageTable, src: &mut PageTable, va: usize, _flags: PageTableFlags) 
+        -> Result<(), PagingError> {
+        share_cow(pt, src, va)
+    }
+    fn handle_page_fault(&self, pt: &mut PageTable, va: usize, _flags: PageTableFlags) -> bool {
+        fault_owned(pt, va)
+    }
+}
+
+// Pages get a zeroed frame of their own on first touch,
+// so only the part of the area actually used costs memory.
+// Like ByFrame, clones share the frames of user pages copy-on-write and user pages may be swapped out.
+#[derive(Clone)]
+pub struct Delay;
+
+impl Delay {
+    pub fn new() -> Self {
+        Delay
+    }
+}
+
+impl MemoryHandler for Delay {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+    fn map(&self, _pt: &mut PageTable, _va: usize, _flags: PageTableFlags) -> Result<(), PagingError> {
+        Ok(())
+    }
+    fn unmap(&self, pt: &mut PageTable, va: usize) {
+        unmap_owned(pt, va);
+    }
+    // pages never touched in `src` stay unmapped in the copy as well
+    fn clone_map(&self, pt: &mut PageTable, src: &mut PageTable, va: usize, _flags: PageTableFlags) 
+        -> Result<(), PagingError> {
+        if src.translate(VirtAddr::new(va)).is_some() || swap::is_swapped(src, va) {
+            share_cow(pt, src, va)
+        } else {
+            Ok(())
+        }
+    }
+    fn handle_page_fault(&self, pt: &mut PageTable, va: usize, flags: PageTableFlags) -> bool {
+        let va = va & !(PAGE_SIZE - 1);
+        if pt.translate(VirtAddr::new(va)).is_some() || swap::is_swapped(pt, va) {
+            return fault_owned(pt, va);
+        }
+        ByFrame::new().map(pt, va, flags).is_ok()
+    }
+}
+
+// Pages which are never mapped, so that running over the end of a
+// neighbouring area faults instead of corrupting memory.
+#[derive(Clone)]
+pub struct Guard;
+
+impl Guard {
+    pub fn new() -> Self {
+        Guard
+    }
+}
+
+impl MemoryHandler for Guard {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+    fn map(&self, _pt: &mut PageTable, _va: usize, _flags: PageTableFlags) -> Result<(), PagingError> {
+        Ok(())
+    }
+    fn unmap(&self, _pt: &mut PageTable, _va: usize) {}
+    fn clone_map(&self, _pt: &mut PageTable, _src: &mut PageTable, _va: usize, _flags: PageTableFlags) 
+        -> Result<(), PagingError> {
+        Ok(())
+    }
+}
+
+// Frames held by a shared area, released when the last address space
+// using them goes away.
+struct SharedFrames(Mutex<BTreeMap<usize, Frame>>);
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        for (_, frame) in self.0.lock().iter() {
+            dealloc_frame(*frame);
+        }
+    }
+}
+
+// Pages backed by frames which are shared by all clones of the area,
+// so writes are visible in every address space it is mapped in.
+#[derive(Clone)]
+pub struct Shared {
+    frames: Arc<SharedFrames>
+}
+
+impl Shared {
+    pub fn new() -> Self {
+        Shared { frames: Arc::new(SharedFrames(Mutex::new(BTreeMap::new()))) }
+    }
+}
+
+impl MemoryHandler for Shared {
+    fn box_clone(&self) -> Box<dyn MemoryHandler> {
+        Box::new(self.clone())
+    }
+    fn map(&self, pt: &mut PageTable, va: usize, flags: PageTableFlags) -> Result<(), PagingError> {
+        let mut frames = (self.frames.0).lock();
+        let frame = match frames.get(&va) {
+            Some(frame) => *frame,
+            None => {
+                let frame = alloc_frame().ok_or(PagingError::NoMemory)?;
+                for b in frame_data(frame).iter_mut() { *b = 0; }
+                frames.insert(va, frame);
+                frame
+            }
+        };
+        pt.map(page_of(va), frame, flags)
+    }
+    fn unmap(&self, pt: &mut PageTable, va: usize) {
+        pt.unmap(page_of(va)).unwrap();
+    }
+    fn clone_map(&self, pt: &mut PageTable, _src: &mut PageTable, va: usize, flags: PageTableFlags) 
+        -> Result<(), PagingError> {
+        self.map(pt, va, flags)
+    }
+}
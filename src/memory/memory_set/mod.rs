@@ -0,0 +1,178 @@
+mod area;
+pub mod handler;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use riscv::addr::{PhysAddr, VirtAddr, Page};
+use crate::consts::{KERNEL_IMAGE_OFFSET, PHYSICAL_MEMORY_OFFSET};
+use crate::memory::paging::{PageTable, PageTableFlags, PagingError};
+use crate::memory::{PHYSICAL_MEMORY, phys_to_virt};
+pub use area::MemoryArea;
+use handler::{MemoryHandler, Linear, Guard};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Read,
+    Write,
+    Execute
+}
+
+// An address space made of memory areas and the page table mapping them
+pub struct MemorySet {
+    areas: Vec<MemoryArea>,
+    page_table: PageTable
+}
+
+impl MemorySet {
+    // an address space with nothing but the kernel mapped
+    pub fn new() -> Result<Self, PagingError> {
+        let mut ms = MemorySet::new_bare()?;
+        ms.map_kernel()?;
+        Ok(ms)
+    }
+
+    // an empty address space, it can not be activated while the kernel runs in it
+    pub fn new_bare() -> Result<Self, PagingError> {
+        Ok(MemorySet {
+            areas: Vec::new(),
+            page_table: PageTable::new().ok_or(PagingError::NoMemory)?
+        })
+    }
+
+    // Map each kernel section with its own permissions, 
+    // plus the physical memory managed by the frame allocator in the direct map.
+    fn map_kernel(&mut self) -> Result<(), PagingError> {
+        extern "C" {
+            fn stext();
+            fn etext();
+            fn srodata();
+            fn erodata();
+            fn sdata();
+            fn bootstackguard();
+            fn bootstack();
+            fn end();
+        }
+        let r = PageTableFlags::READABLE;
+        let w = PageTableFlags::WRITABLE;
+        let x = PageTableFlags::EXECUTABLE;
+        let offset = KERNEL_IMAGE_OFFSET;
+        self.push(stext as usize, etext as usize, r | x, Linear::new(offset))?;
+        self.push(srodata as usize, erodata as usize, r, Linear::new(offset))?;
+        // .data, .stack and .bss, but for the guard page below the boot stack
+        self.push(sdata as usize, bootstackguard as usize, r | w, Linear::new(offset))?;
+        self.push(bootstackguard as usize, bootstack as usize, PageTableFlags::empty(), Guard::new())?;
+        self.push(bootstack as usize, end as usize, r | w, Linear::new(offset))?;
+        let physical_memory = PHYSICAL_MEMORY.lock();
+        for region in physical_memory.regions() {
+            let start = phys_to_virt(PhysAddr::new(region.start)).as_usize();
+            let end = start + (region.end - region.start);
+            self.push(start, end, r | w, Linear::new(PHYSICAL_MEMORY_OFFSET))?;
+        }
+        Ok(())
+    }
+
+    // add the area [start, end) and map it
+    pub fn push<T: MemoryHandler>(&mut self, start: usize, end: usize, 
+                                  flags: PageTableFlags, handler: T) -> Result<(), PagingError> {
+        assert!(start < end);
+        let area = MemoryArea::new(start, end, flags, Box::new(handler));
+        if self.areas.iter().any(|a| a.is_overlap_with(area.start(), area.end())) {
+            return Err(PagingError::AlreadyMapped);
+        }
+        area.map(&mut self.page_table)?;
+        self.areas.push(area);
+        Ok(())
+    }
+
+    // unmap and drop the area which begins at `start`
+    pub fn remove(&mut self, start: usize) -> Result<(), PagingError> {
+        let idx = self.areas.iter().position(|a| a.start() == start)
+            .ok_or(PagingError::NotMapped)?;
+        let area = self.areas.remove(idx);
+        area.unmap(&mut self.page_table);
+        Ok(())
+    }
+
+    pub fn find_area(&self, va: usize) -> Option<&MemoryArea> {
+        self.areas.iter().find(|a| a.contains(va))
+    }
+
+    // the lowest address from `start` where `size` bytes are in no area and end before `end`
+    pub fn find_free(&self, start: usize, end: usize, size: usize) -> Option<usize> {
+        let mut va = start;
+        while va <= end && end - va >= size {
+            match self.areas.iter().filter(|a| a.is_overlap_with(va, va + size)).map(|a| a.end()).max() {
+                Some(next) => va = next,
+                None => return Some(va)
+            }
+        }
+        None
+    }
+
+    // Resolve a page fault at `va`, return false if the access is invalid,
+    // i.e. `va` is in no area or the area does not allow the access.
+    pub fn handle_page_fault(&mut self, va: usize, access: AccessType) -> bool {
+        let area = match self.areas.iter().find(|a| a.contains(va)) {
+            Some(area) => area,
+            None => return false
+        };
+        let required = match access {
+            AccessType::Read => PageTableFlags::READABLE,
+            AccessType::Write => PageTableFlags::WRITABLE,
+            AccessType::Execute => PageTableFlags::EXECUTABLE
+        };
+        if !area.flags().contains(required) {
+            return false;
+        }
+        let page = Page::of_addr(VirtAddr::new(va));
+        if let Some(entry) = self.page_table.get_entry(page) {
+            // harts which trap instead of setting the accessed and dirty bits fault on
+            // pages allowing the access, e.g. after the clock algorithm cleared A
+            let touched = match access {
+                AccessType::Write => PageTableFlags::ACCESSED | PageTableFlags::DIRTY,
+                _ => PageTableFlags::ACCESSED
+            };
+            let flags = entry.flags();
+            if flags.contains(required) && !flags.contains(touched) {
+                return self.page_table.update_flags(page, flags | touched).is_ok();
+            }
+        }
+        area.handler().handle_page_fault(&mut self.page_table, va, area.flags())
+    }
+
+    pub fn page_table(&self) -> &PageTable {
+        &self.page_table
+    }
+
+    pub fn page_table_mut(&mut self) -> &mut PageTable {
+        &mut self.page_table
+    }
+
+    pub fn token(&self) -> usize {
+        self.page_table.token()
+    }
+
+    pub unsafe fn activate(&self) {
+        self.page_table.activate();
+    }
+
+    // Build a new address space with the same areas, their content is 
+    // shared or copied-on-write as their handlers decide
+    pub fn try_clone(&mut self) -> Result<MemorySet, PagingError> {
+        let mut ms = MemorySet::new_bare()?;
+        for area in self.areas.iter() {
+            let new_area = area.clone_map(&mut ms.page_table, &mut self.page_table)?;
+            ms.areas.push(new_area);
+        }
+        Ok(ms)
+    }
+}
+
+impl Drop for MemorySet {
+    // return the frames of every area before the page table frees its own
+    fn drop(&mut self) {
+        for area in self.areas.iter() {
+            area.unmap(&mut self.page_table);
+        }
+    }
+}
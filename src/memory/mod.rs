@@ -1,11 +1,31 @@
+mod addr;
 mod allocator;
 mod frame_allocator;
 mod mutexed_allocator;
 mod buddy_allocator;
 mod slub_allocator;
 mod hybrid_allocator;
+mod region_allocator;
+#[cfg(feature = "heap-tlsf")]
+mod tlsf_allocator;
+#[cfg(feature = "leak-track")]
+mod leak_tracker;
+mod kmem_cache;
+mod reclaim;
+mod layout;
+mod vmalloc;
+mod kernel_stack;
+pub mod paging;
+pub mod memory_set;
+pub mod swap;
 
-use frame_allocator::SEGMENT_TREE_ALLOCATOR as FRAME_ALLOCATOR;
+use core::cmp::{min, max};
+use frame_allocator::{BitmapAllocator, BITMAP_ALLOCATOR as FRAME_ALLOCATOR};
+use crate::consts::{MAX_PHYSICAL_ADDR, PAGE_SIZE, VMALLOC_START, VMALLOC_END};
+use crate::fdt::Fdt;
+use memory_set::MemorySet;
+pub use memory_set::AccessType;
+use spin::Mutex;
 use riscv::addr::{
     VirtAddr,
     PhysAddr,
@@ -13,43 +33,374 @@ use riscv::addr::{
     Frame
 };
 
-pub fn init(l: usize, r: usize) {
-    FRAME_ALLOCATOR.lock().init(l, r);
+pub use addr::{phys_to_virt, virt_to_phys, frame_data};
+pub use layout::{MemoryLayout, Region};
+pub use frame_allocator::FrameStats;
+pub use hybrid_allocator::HeapStats;
+pub use kmem_cache::KmemCache;
+pub use vmalloc::{vmalloc, vfree};
+pub use kernel_stack::{KernelStack, stack_overflow};
+#[cfg(feature = "leak-track")]
+pub use kernel_stack::current as current_kernel_stack;
+
+fn page_up(addr: usize) -> usize { (addr + PAGE_SIZE - 1) / PAGE_SIZE }
+fn page_down(addr: usize) -> usize { addr / PAGE_SIZE }
+
+// Initialize the frame allocator with the usable physical memory regions
+pub fn init(layout: &MemoryLayout) {
+    // before the heap, the leak tracker walks the stack on every allocation
+    kernel_stack::init();
+    let regions = layout.regions();
+    assert!(!regions.is_empty(), "Memory: No usable physical memory.");
+    let l = page_up(regions[0].start);
+    let mut r = page_down(regions[regions.len() - 1].end);
+    if r > page_down(MAX_PHYSICAL_ADDR) {
+        println!("Memory: Pages from 0x{:x} are out of reach and not managed.", 
+                 page_down(MAX_PHYSICAL_ADDR));
+        r = page_down(MAX_PHYSICAL_ADDR);
+    }
+    // the bookkeeping of the frame allocator takes the first pages large enough for it
+    let meta_pages = page_up(BitmapAllocator::meta_size(r - l));
+    let meta = regions.iter()
+        .map(|region| (page_up(region.start), min(page_down(region.end), r)))
+        .find(|&(start, end)| start + meta_pages <= end)
+        .map(|(start, _)| start)
+        .expect("Memory: No room for the frame allocator.");
+    println!("Memory: Frame allocator takes 0x{:x} pages from 0x{:x}.", meta_pages, meta);
+    {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        allocator.init(l, r, phys_to_virt(PhysAddr::new(meta * PAGE_SIZE)).as_usize());
+        // pages in the holes between regions are not usable
+        let mut next = l;
+        for region in regions {
+            let start = min(page_up(region.start), r);
+            if start > next {
+                allocator.reserve(next, start);
+            }
+            next = max(next, min(page_down(region.end), r));
+        }
+        allocator.reserve(meta, meta + meta_pages);
+    }
+    {
+        let mut physical_memory = PHYSICAL_MEMORY.lock();
+        for region in regions {
+            let start = min(page_up(region.start), r);
+            let end = min(page_down(region.end), r);
+            if start < end {
+                physical_memory.add(start * PAGE_SIZE, end * PAGE_SIZE);
+            }
+        }
+    }
+    init_heap();
+    remap_kernel();
     println!("Memory: Setup done.");
 }
 
+// Usable physical memory managed by the frame allocator, in whole pages.
+// Every address space maps it in the direct map.
+pub static PHYSICAL_MEMORY: Mutex<MemoryLayout> = Mutex::new(MemoryLayout::new());
+
+static KERNEL_MEMORY_SET: Mutex<Option<MemorySet>> = Mutex::new(None);
+
+// Replace the boot page table, which maps the whole first GiB as RWX, 
+// with the kernel address space mapping each section with its own permissions.
+fn remap_kernel() {
+    let ms = MemorySet::new().expect("Memory: Failed to build the kernel address space.");
+    unsafe { ms.activate(); }
+    *KERNEL_MEMORY_SET.lock() = Some(ms);
+    println!("Memory: Remap kernel done.");
+}
+
+// Run `f` on the kernel address space
+pub fn with_kernel_memory_set<T, F: FnOnce(&mut MemorySet) -> T>(f: F) -> T {
+    f(KERNEL_MEMORY_SET.lock().as_mut().expect("Memory: Kernel address space is not ready."))
+}
+
+// Handle a page fault at virtual address `va` in the faulting address space.
+// There are no user address spaces yet, so it is always the kernel one.
+pub fn handle_page_fault(va: usize, access: AccessType) -> bool {
+    // a fault inside with_kernel_memory_set would spin on the lock forever,
+    // with a single hart the lock can only be held by the faulting code
+    let mut ms = KERNEL_MEMORY_SET.try_lock()
+        .unwrap_or_else(|| panic!("Memory: Page fault at 0x{:x} with the kernel address space locked.", va));
+    ms.as_mut().expect("Memory: Kernel address space is not ready.").handle_page_fault(va, access)
+}
+
+// Find out the usable physical memory from the device tree at physical 
+// address `dtb`, leaving out the kernel image, everything below it 
+// and the device tree itself.
+pub fn detect_layout(dtb: usize) -> MemoryLayout {
+    extern "C" {
+        fn end();
+    }
+    let fdt = unsafe { Fdt::from_addr(phys_to_virt(PhysAddr::new(dtb)).as_usize()) }
+        .expect("Memory: Invalid device tree blob.");
+    let mut layout = MemoryLayout::from_fdt(&fdt);
+    layout.remove(0, virt_to_phys(VirtAddr::new(end as usize)).as_usize());
+    layout.remove(dtb, dtb + fdt.total_size());
+    layout
+}
+
+pub use reclaim::Reclaimer;
+
+// Run `alloc` for `count` frames, asking the registered reclaimers to release
+// frames whenever it fails. Reclaim stops once a pass releases fewer than `count`
+// frames, and is not tried at all for more frames than are managed, so that a
+// request which cannot succeed does not drain every cache first.
+fn alloc_reclaiming<F: Fn() -> Option<usize>>(count: usize, alloc: F) -> Option<Frame> {
+    loop {
+        if let Some(ppn) = alloc() {
+            return Some(Frame::of_ppn(ppn));
+        }
+        if count > FRAME_ALLOCATOR.lock().stats().total {
+            return None;
+        }
+        // the last pass may still have released enough
+        if reclaim::run() < count {
+            return alloc().map(Frame::of_ppn);
+        }
+    }
+}
+
+// Allocate a physical frame. When physical memory runs out, the registered
+// reclaimers are asked to release frames before giving up with None.
 pub fn alloc_frame() -> Option<Frame> {
-    Some(Frame::of_ppn(FRAME_ALLOCATOR.lock().alloc()))
+    alloc_reclaiming(1, || FRAME_ALLOCATOR.lock().alloc())
 }
 
+// Drop a reference to a frame, it is deallocated when nobody else shares it
 pub fn dealloc_frame(f: Frame) {
     FRAME_ALLOCATOR.lock().dealloc(f.number());
 }
 
+// Add a reference to an allocated frame, e.g. when it becomes shared by copy-on-write.
+// Return false if the frame has as many references as it can take.
+pub fn share_frame(f: Frame) -> bool {
+    FRAME_ALLOCATOR.lock().share(f.number())
+}
+
+pub fn frame_ref_count(f: Frame) -> usize {
+    FRAME_ALLOCATOR.lock().ref_count(f.number())
+}
+
+// Allocate `count` physically contiguous frames. The number of the first frame
+// is a multiple of 2^align_log2, e.g. align_log2 = 9 gives a 2MiB aligned run.
+pub fn alloc_contiguous(count: usize, align_log2: usize) -> Option<Frame> {
+    alloc_reclaiming(count, || FRAME_ALLOCATOR.lock().alloc_contiguous(count, align_log2))
+}
+
+// Deallocate `count` contiguous frames beginning at `f`.
+pub fn dealloc_contiguous(f: Frame, count: usize) {
+    FRAME_ALLOCATOR.lock().dealloc_contiguous(f.number(), count);
+}
+
+// Register a callback which is run when frame allocation fails, 
+// e.g. to shrink caches or drop clean pages. 
+// Return false if there is no room for more reclaimers.
+pub fn register_reclaimer(f: Reclaimer) -> bool {
+    reclaim::register(f)
+}
+
+pub fn unregister_reclaimer(f: Reclaimer) {
+    reclaim::unregister(f);
+}
+
 use mutexed_allocator::MutexedAllocator;
-use buddy_allocator::BuddyAllocator;
-use hybrid_allocator::HybridAllocator;
-use crate::consts::KERNEL_HEAP_SIZE;
+use allocator::{DynamicAllocator, RegionHeap};
+use allocator::next_pow_of_2;
+use crate::consts::{KERNEL_HEAP_SIZE, KERNEL_HEAP_GROW_SIZE};
+
+// The heap is slub caches over buddy allocators unless another one is chosen by a feature
+#[cfg(all(feature = "heap-buddy", feature = "heap-tlsf"))]
+compile_error!("Only one of the heap-buddy and heap-tlsf features can be enabled.");
+
+#[cfg(feature = "heap-buddy")]
+type KernelHeap = region_allocator::RegionAllocator;
+#[cfg(feature = "heap-tlsf")]
+type KernelHeap = tlsf_allocator::TlsfAllocator;
+#[cfg(not(any(feature = "heap-buddy", feature = "heap-tlsf")))]
+type KernelHeap = hybrid_allocator::HybridAllocator;
+
+#[cfg(not(feature = "leak-track"))]
+#[global_allocator]
+static KERNEL_DYNAMIC_ALLOCATOR: MutexedAllocator<KernelHeap> = 
+    MutexedAllocator::with_grow(KernelHeap::new(), grow_heap);
+
+#[cfg(feature = "leak-track")]
+use leak_tracker::LeakTracker;
 
+#[cfg(feature = "leak-track")]
 #[global_allocator]
-static KERNEL_DYNAMIC_ALLOCATOR: MutexedAllocator<HybridAllocator> = 
-    MutexedAllocator::new(HybridAllocator::new());
-    // MutexedAllocator::new(BuddyAllocator::new());
+static KERNEL_DYNAMIC_ALLOCATOR: LeakTracker<MutexedAllocator<KernelHeap>> = 
+    LeakTracker::new(MutexedAllocator::with_grow(KernelHeap::new(), grow_heap));
+
+// Heap allocations made later are newer than the returned mark,
+// e.g. to check that a subsystem frees everything on teardown.
+#[cfg(feature = "leak-track")]
+pub fn leak_mark() -> usize {
+    KERNEL_DYNAMIC_ALLOCATOR.mark()
+}
+
+// Print the live heap allocations made after `mark` grouped by call site, return their number
+#[cfg(feature = "leak-track")]
+pub fn print_leaks(mark: usize) -> usize {
+    let mut total = 0;
+    KERNEL_DYNAMIC_ALLOCATOR.for_each_site(mark, |site, count, bytes| {
+        print!("Heap: {} live allocations of 0x{:x} bytes from", count, bytes);
+        for ra in site.iter().take_while(|&&ra| ra != 0) {
+            print!(" 0x{:x}", ra);
+        }
+        println!();
+        total += count;
+    });
+    let untracked = KERNEL_DYNAMIC_ALLOCATOR.untracked();
+    if untracked > 0 {
+        println!("Heap: {} live allocations are not tracked.", untracked);
+    }
+    total
+}
 
 #[alloc_error_handler]
-fn alloc_error_handler(_: core::alloc::Layout) -> ! {
+fn alloc_error_handler(layout: core::alloc::Layout) -> ! {
+    println!("Heap: Failed to allocate 0x{:x} bytes aligned to 0x{:x}.", layout.size(), layout.align());
+    // the lock is only held if the failure happened inside the allocator
+    let stats = KERNEL_DYNAMIC_ALLOCATOR.try_lock().map(|heap| heap.heap_stats());
+    if let Some(ref stats) = stats {
+        print_heap_stats(stats);
+    }
     panic!("Dynamic allocation failed!");
 }
 
-pub fn init_heap() {
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
+pub fn heap_stats() -> HeapStats {
+    KERNEL_DYNAMIC_ALLOCATOR.lock().heap_stats()
+}
+
+// Print the usage of physical memory and of the heap in the style of /proc/meminfo
+pub fn print_meminfo() {
+    let frames = frame_stats();
+    println!("MemTotal:       {:>10} kB", frames.total * PAGE_SIZE / 1024);
+    println!("MemFree:        {:>10} kB", frames.free * PAGE_SIZE / 1024);
+    println!("MemUsed:        {:>10} kB", (frames.total - frames.free) * PAGE_SIZE / 1024);
+    println!("VmallocTotal:   {:>10} kB", (VMALLOC_END - VMALLOC_START) / 1024);
+    println!("VmallocUsed:    {:>10} kB", vmalloc::vmalloc_used() / 1024);
+    print_heap_stats(&heap_stats());
+}
+
+fn print_heap_stats(stats: &HeapStats) {
+    let back = &stats.back;
+    println!("HeapRegions:    {:>10}", back.regions);
+    println!("HeapTotal:      {:>10} kB", back.size / 1024);
+    println!("HeapFree:       {:>10} kB", back.free.free_bytes / 1024);
+    println!("HeapLargestFree:{:>10} kB", back.free.largest_free / 1024);
+    // free blocks of each order up to the largest one, like /proc/buddyinfo
+    let orders = back.free.free_blocks.iter().rposition(|&n| n > 0).map_or(0, |i| i + 1);
+    print!("HeapFreeBlocks: ");
+    for n in back.free.free_blocks[..orders].iter() {
+        print!(" {}", n);
+    }
+    println!();
+    // heaps without slub caches leave them empty
+    for pool in stats.slub.iter().filter(|pool| pool.size != 0) {
+        println!("Slab{:>5}:     {:>10} objects, frames {} current {} partial {} full",
+                 pool.size, pool.objects_in_use, pool.current_frames, pool.partial_frames, pool.full_frames);
+    }
+}
+
+// Object caches take their frames right from the frame allocator,
+// they are reached through the direct map.
+pub struct CacheFrames;
+
+impl DynamicAllocator for CacheFrames {
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let align_log2 = (max(align, PAGE_SIZE) / PAGE_SIZE).trailing_zeros() as usize;
+        let frame = alloc_contiguous(page_up(size), align_log2)?;
+        Some(phys_to_virt(frame.start_address()).as_usize())
+    }
+    fn dealloc(&mut self, addr: usize, size: usize) {
+        let frame = Frame::of_addr(virt_to_phys(VirtAddr::new(addr)));
+        dealloc_contiguous(frame, page_up(size));
+    }
+    fn grained(&self, minsz: usize) -> usize {
+        next_pow_of_2(max(minsz, PAGE_SIZE))
+    }
+    fn resize_in_place(&mut self, _addr: usize, _size: usize, _new_size: usize) -> bool {
+        false
+    }
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize> {
+        let addr = self.alloc(size, align)?;
+        unsafe { core::ptr::write_bytes(addr as *mut u8, 0, size); }
+        Some(addr)
+    }
+}
+
+// A cache of kernel objects of type T, e.g.
+// static TASKS: KernelCache<Task> = KmemCache::new("task", None, None, CacheFrames);
+pub type KernelCache<T> = KmemCache<T, CacheFrames>;
+
+pub fn print_cache_stats<T>(cache: &KernelCache<T>) {
+    let stats = cache.stats();
+    println!("Cache {:<10} {:>10} objects of {} bytes, frames {} current {} partial {} full",
+             cache.name(), stats.objects_in_use, stats.size,
+             stats.current_frames, stats.partial_frames, stats.full_frames);
+}
+
+fn init_heap() {
     static mut HEAP: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
     println!("Initialize heap at 0x{:x} with size 0x{:x}.", 
              unsafe { &HEAP as *const _ as usize }, KERNEL_HEAP_SIZE);
     unsafe {
         KERNEL_DYNAMIC_ALLOCATOR
             .lock()
-            .init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE);
+            .init(HEAP.as_ptr() as usize, KERNEL_HEAP_SIZE, true);
     }
+    assert!(register_reclaimer(shrink_heap), "Memory: No room to register the heap reclaimer.");
     println!("Memory: Initializing heap done.")
 }
 
+// Add direct mapped frames to the heap so that `size` bytes aligned to `align` fit,
+// return false if there are not enough contiguous frames.
+fn grow_heap(size: usize, align: usize) -> bool {
+    let bytes = KERNEL_DYNAMIC_ALLOCATOR.lock().region_size_for(size, align)
+        .and_then(|bytes| bytes.checked_add(PAGE_SIZE - 1));
+    let bytes = match bytes {
+        Some(bytes) => max(bytes & !(PAGE_SIZE - 1), KERNEL_HEAP_GROW_SIZE),
+        None => return false
+    };
+    let pages = bytes / PAGE_SIZE;
+    // aligned to the largest power of two dividing the size, as `region_size_for` expects
+    let frame = match alloc_contiguous(pages, pages.trailing_zeros() as usize) {
+        Some(frame) => frame,
+        None => return false
+    };
+    let start = phys_to_virt(frame.start_address()).as_usize();
+    if !KERNEL_DYNAMIC_ALLOCATOR.lock().add_region(start, bytes, true, false) {
+        dealloc_contiguous(frame, pages);
+        return false;
+    }
+    true
+}
+
+// Give the regions added by `grow_heap` back once nothing is allocated from them
+fn shrink_heap() -> usize {
+    let mut released = 0;
+    loop {
+        // the heap is locked if it is the one running out of frames
+        let region = match KERNEL_DYNAMIC_ALLOCATOR.try_lock() {
+            Some(mut heap) => heap.take_unused(),
+            None => break
+        };
+        let (start, size) = match region {
+            Some(region) => region,
+            None => break
+        };
+        let frame = Frame::of_addr(virt_to_phys(VirtAddr::new(start)));
+        dealloc_contiguous(frame, size / PAGE_SIZE);
+        released += size / PAGE_SIZE;
+    }
+    released
+}
+
@@ -1,16 +1,52 @@
 extern crate spin;
 
 use core::ops::Deref;
+use core::cmp::min;
+use core::ptr;
 use core::alloc::{GlobalAlloc, Layout};
 use spin::Mutex;
 use crate::memory::allocator::DynamicAllocator;
 use crate::memory::buddy_allocator::BuddyAllocator;
 
-pub struct MutexedAllocator<T: DynamicAllocator>(Mutex<T>);
+pub struct MutexedAllocator<T: DynamicAllocator> {
+    inner: Mutex<T>,
+    // called without holding the lock when an allocation of (size, align) fails,
+    // returns false if no more memory can be added
+    grow: Option<fn(usize, usize) -> bool>
+}
 
 impl<T: DynamicAllocator> MutexedAllocator<T> {
     pub const fn new(c: T) -> Self {
-        MutexedAllocator(Mutex::new(c)) 
+        MutexedAllocator { inner: Mutex::new(c), grow: None }
+    }
+    pub const fn with_grow(c: T, grow: fn(usize, usize) -> bool) -> Self {
+        MutexedAllocator { inner: Mutex::new(c), grow: Some(grow) }
+    }
+    // Blocks are allocated and freed with their size padded to the alignment, so the size
+    // alone tells where a block is from: the slub size class of a padded size is always
+    // aligned enough, and more alignment than the largest class pads it past slub.
+    fn padded_size(layout: &Layout) -> usize {
+        (layout.size() + layout.align() - 1) & !(layout.align() - 1)
+    }
+    fn alloc_with(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+        let size = Self::padded_size(&layout);
+        let try_alloc = || if zeroed {
+            self.lock().alloc_zeroed(size, layout.align())
+        } else {
+            self.lock().alloc(size, layout.align())
+        };
+        let mut res = try_alloc();
+        // the memory added is meant to fit the request, if it still fails growing again will not help
+        if let (None, Some(grow)) = (res, self.grow) {
+            if grow(size, layout.align()) {
+                res = try_alloc();
+            }
+        }
+        match res {
+            Some(addr) => addr as *mut u8,
+            // leave it to the caller, e.g. try_reserve or alloc_error_handler
+            None => ptr::null_mut()
+        }
     }
 }
 
@@ -18,17 +54,31 @@ impl<T: DynamicAllocator> Deref for MutexedAllocator<T> {
     type Target = Mutex<T>;
 
     fn deref(&self) -> &Mutex<T> {
-        &self.0
+        &self.inner
     }
 }
 
 unsafe impl<T: DynamicAllocator> GlobalAlloc for MutexedAllocator<T> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let res = self.lock().alloc(layout.size(), layout.align()).unwrap();
-        res as *mut u8
+        self.alloc_with(layout, false)
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        self.lock().dealloc(ptr as usize);
+        self.lock().dealloc(ptr as usize, Self::padded_size(&layout));
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, true)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if self.lock().resize_in_place(ptr as usize, Self::padded_size(&layout), Self::padded_size(&new_layout)) {
+            return ptr;
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
 
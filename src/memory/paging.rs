@@ -0,0 +1,393 @@
+use core::mem::ManuallyDrop;
+use core::ops::{BitOr, BitOrAssign};
+use riscv::addr::{
+    VirtAddr,
+    PhysAddr,
+    Page,
+    Frame
+};
+use crate::consts::PAGE_SIZE;
+use crate::memory::{alloc_frame, dealloc_frame, phys_to_virt};
+
+pub const ENTRIES_PER_TABLE: usize = 512;
+// number of levels of an Sv39 page table, level 2 is the root
+const LEVELS: usize = 3;
+const PPN_MASK: usize = (1 << 44) - 1;
+const SATP_MODE_SV39: usize = 8 << 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageTableFlags(usize);
+
+impl PageTableFlags {
+    pub const VALID: Self = PageTableFlags(1 << 0);
+    pub const READABLE: Self = PageTableFlags(1 << 1);
+    pub const WRITABLE: Self = PageTableFlags(1 << 2);
+    pub const EXECUTABLE: Self = PageTableFlags(1 << 3);
+    pub const USER: Self = PageTableFlags(1 << 4);
+    pub const GLOBAL: Self = PageTableFlags(1 << 5);
+    pub const ACCESSED: Self = PageTableFlags(1 << 6);
+    pub const DIRTY: Self = PageTableFlags(1 << 7);
+    // bits 8 and 9 are reserved for software
+    pub const COPY_ON_WRITE: Self = PageTableFlags(1 << 8);
+    // set in invalid entries whose page is in the swap space
+    pub const SWAPPED: Self = PageTableFlags(1 << 9);
+
+    pub const fn empty() -> Self { PageTableFlags(0) }
+    pub fn bits(&self) -> usize { self.0 }
+    pub fn contains(&self, other: Self) -> bool { self.0 & other.0 == other.0 }
+    pub fn insert(&mut self, other: Self) { self.0 |= other.0; }
+    pub fn remove(&mut self, other: Self) { self.0 &= !other.0; }
+}
+
+impl BitOr for PageTableFlags {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self { PageTableFlags(self.0 | other.0) }
+}
+
+impl BitOrAssign for PageTableFlags {
+    fn bitor_assign(&mut self, other: Self) { self.0 |= other.0; }
+}
+
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntry(usize);
+
+impl PageTableEntry {
+    pub const fn empty() -> Self { PageTableEntry(0) }
+    pub const fn from_bits(bits: usize) -> Self { PageTableEntry(bits) }
+    pub fn new(frame: Frame, flags: PageTableFlags) -> Self {
+        PageTableEntry((frame.number() << 10) | flags.bits())
+    }
+    pub fn bits(&self) -> usize { self.0 }
+    pub fn is_valid(&self) -> bool { self.0 & PageTableFlags::VALID.bits() != 0 }
+    // a valid entry with any of R, W, X set points to a page, otherwise to the next level table
+    pub fn is_leaf(&self) -> bool {
+        let rwx = PageTableFlags::READABLE | PageTableFlags::WRITABLE | PageTableFlags::EXECUTABLE;
+        self.is_valid() && self.0 & rwx.bits() != 0
+    }
+    pub fn flags(&self) -> PageTableFlags { PageTableFlags(self.0 & 0x3ff) }
+    pub fn frame(&self) -> Frame { Frame::of_ppn((self.0 >> 10) & PPN_MASK) }
+    pub fn set(&mut self, frame: Frame, flags: PageTableFlags) { *self = PageTableEntry::new(frame, flags); }
+    pub fn set_flags(&mut self, flags: PageTableFlags) { self.0 = (self.0 & !0x3ff) | flags.bits(); }
+    pub fn clear(&mut self) { self.0 = 0; }
+}
+
+#[derive(Debug)]
+pub enum PagingError {
+    // the page is already mapped
+    AlreadyMapped,
+    // the page is not mapped
+    NotMapped,
+    // a huge page is mapped over the page
+    HugePage,
+    // the address is not aligned to the page size
+    Unaligned,
+    // no frame left for a page table
+    NoMemory
+}
+
+// Sizes of the leaf entries of an Sv39 page table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G
+}
+
+impl PageSize {
+    // from the largest to the smallest
+    pub const ALL: [PageSize; 3] = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K];
+
+    fn of_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Size4K,
+            1 => PageSize::Size2M,
+            _ => PageSize::Size1G
+        }
+    }
+    // level of the table holding leaf entries of this size
+    pub fn level(&self) -> usize {
+        match self {
+            PageSize::Size4K => 0,
+            PageSize::Size2M => 1,
+            PageSize::Size1G => 2
+        }
+    }
+    pub fn bytes(&self) -> usize {
+        PAGE_SIZE << (9 * self.level())
+    }
+}
+
+// index of the entry in the table at `level` that translates `va`
+fn table_index(va: usize, level: usize) -> usize {
+    (va >> (12 + 9 * level)) & (ENTRIES_PER_TABLE - 1)
+}
+
+fn table_of(frame: Frame) -> &'static mut [PageTableEntry; ENTRIES_PER_TABLE] {
+    let va = phys_to_virt(frame.start_address()).as_usize();
+    unsafe { &mut *(va as *mut [PageTableEntry; ENTRIES_PER_TABLE]) }
+}
+
+fn alloc_table() -> Option<Frame> {
+    let frame = alloc_frame()?;
+    for entry in table_of(frame).iter_mut() { entry.clear(); }
+    Some(frame)
+}
+
+fn free_table(frame: Frame, level: usize) {
+    if level > 0 {
+        for entry in table_of(frame).iter() {
+            if entry.is_valid() && !entry.is_leaf() {
+                free_table(entry.frame(), level - 1);
+            }
+        }
+    }
+    dealloc_frame(frame);
+}
+
+// Replace the huge leaf `entry` at `level` by a table of smaller leaves
+// mapping the same memory with the same flags
+fn split_huge(entry: &mut PageTableEntry, level: usize) -> Result<(), PagingError> {
+    let table = alloc_table().ok_or(PagingError::NoMemory)?;
+    let base = entry.frame().number();
+    let flags = entry.flags();
+    let step = 1 << (9 * (level - 1));
+    for (i, e) in table_of(table).iter_mut().enumerate() {
+        e.set(Frame::of_ppn(base + i * step), flags);
+    }
+    entry.set(table, PageTableFlags::VALID);
+    Ok(())
+}
+
+fn read_satp() -> usize {
+    let satp: usize;
+    unsafe { asm!("csrr $0, satp" : "=r"(satp) ::: "volatile"); }
+    satp
+}
+
+// flush the TLB entries of one virtual address
+pub fn flush_tlb(va: usize) {
+    unsafe { asm!("sfence.vma $0, zero" :: "r"(va) : "memory" : "volatile"); }
+}
+
+pub fn flush_tlb_all() {
+    unsafe { asm!("sfence.vma" ::: "memory" : "volatile"); }
+}
+
+// Sv39 page table owning the frames of its tables but not the mapped frames
+pub struct PageTable {
+    root: Frame
+}
+
+impl PageTable {
+    pub fn new() -> Option<Self> {
+        Some(PageTable { root: alloc_table()? })
+    }
+
+    // Access the page table whose root is `root` without owning it.
+    // The caller must make sure that the page table is alive.
+    pub unsafe fn from_root(root: Frame) -> ManuallyDrop<PageTable> {
+        ManuallyDrop::new(PageTable { root })
+    }
+
+    pub fn root_frame(&self) -> Frame {
+        self.root
+    }
+
+    // value of satp which selects this page table
+    pub fn token(&self) -> usize {
+        SATP_MODE_SV39 | self.root.number()
+    }
+
+    pub fn is_active(&self) -> bool {
+        read_satp() & PPN_MASK == self.root.number()
+    }
+
+    // Switch to this page table. The caller has to make sure that the
+    // code and stack currently in use are mapped the same way in it.
+    pub unsafe fn activate(&self) {
+        asm!("csrw satp, $0" :: "r"(self.token()) :: "volatile");
+        flush_tlb_all();
+    }
+
+    // Find the entry of `va` in the table at `level`, creating missing tables if `create`.
+    // Huge pages above `level` are split if `split`, otherwise they are an error.
+    fn walk(&mut self, va: usize, level: usize, create: bool, split: bool)
+        -> Result<&'static mut PageTableEntry, PagingError> {
+        let mut table = table_of(self.root);
+        for l in ((level + 1)..LEVELS).rev() {
+            let entry = &mut table[table_index(va, l)];
+            if !entry.is_valid() {
+                if !create {
+                    return Err(PagingError::NotMapped);
+                }
+                let frame = alloc_table().ok_or(PagingError::NoMemory)?;
+                entry.set(frame, PageTableFlags::VALID);
+            } else if entry.is_leaf() {
+                if !split {
+                    return Err(PagingError::HugePage);
+                }
+                split_huge(entry, l)?;
+            }
+            table = table_of(entry.frame());
+        }
+        Ok(&mut table[table_index(va, level)])
+    }
+
+    // find the level 0 entry of `va`, creating missing tables if `create`
+    fn entry(&mut self, va: usize, create: bool) -> Result<&'static mut PageTableEntry, PagingError> {
+        self.walk(va, 0, create, false)
+    }
+
+    // the leaf entry mapping `va` whatever its size
+    fn leaf(&self, va: usize) -> Option<(&'static mut PageTableEntry, PageSize)> {
+        let mut table = table_of(self.root);
+        for level in (0..LEVELS).rev() {
+            let entry = &mut table[table_index(va, level)];
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some((entry, PageSize::of_level(level)));
+            }
+            table = table_of(entry.frame());
+        }
+        None
+    }
+
+    fn flush(&self, va: usize) {
+        if self.is_active() {
+            flush_tlb(va);
+        }
+    }
+
+    pub fn map(&mut self, page: Page, frame: Frame, flags: PageTableFlags) -> Result<(), PagingError> {
+        let va = page.start_address().as_usize();
+        let entry = self.entry(va, true)?;
+        if entry.is_valid() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        entry.set(frame, flags | PageTableFlags::VALID);
+        self.flush(va);
+        Ok(())
+    }
+
+    // Unmap a page and return the frame it was mapped to,
+    // it is up to the caller to deallocate the frame.
+    // A huge page covering it is split and the rest of it stays mapped.
+    pub fn unmap(&mut self, page: Page) -> Result<Frame, PagingError> {
+        let va = page.start_address().as_usize();
+        let entry = self.walk(va, 0, false, true)?;
+        if !entry.is_valid() {
+            return Err(PagingError::NotMapped);
+        }
+        let frame = entry.frame();
+        entry.clear();
+        self.flush(va);
+        Ok(frame)
+    }
+
+    // change the flags of one page, splitting a huge page covering it
+    pub fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> Result<(), PagingError> {
+        let va = page.start_address().as_usize();
+        let entry = self.walk(va, 0, false, true)?;
+        if !entry.is_valid() {
+            return Err(PagingError::NotMapped);
+        }
+        entry.set_flags(flags | PageTableFlags::VALID);
+        self.flush(va);
+        Ok(())
+    }
+
+    // Map `size` bytes at `va` to `pa` with a single leaf entry,
+    // both addresses have to be aligned to `size`.
+    pub fn map_huge(&mut self, va: VirtAddr, pa: PhysAddr, size: PageSize, flags: PageTableFlags)
+        -> Result<(), PagingError> {
+        let (va, pa) = (va.as_usize(), pa.as_usize());
+        if va & (size.bytes() - 1) != 0 || pa & (size.bytes() - 1) != 0 {
+            return Err(PagingError::Unaligned);
+        }
+        let entry = self.walk(va, size.level(), true, false)?;
+        if entry.is_valid() {
+            return Err(PagingError::AlreadyMapped);
+        }
+        entry.set(Frame::of_addr(PhysAddr::new(pa)), flags | PageTableFlags::VALID);
+        self.flush(va);
+        Ok(())
+    }
+
+    // Unmap the whole leaf mapping `va`, which has to be its first address,
+    // and return the physical address and the size it mapped.
+    pub fn unmap_huge(&mut self, va: VirtAddr) -> Result<(PhysAddr, PageSize), PagingError> {
+        let va = va.as_usize();
+        let (entry, size) = self.leaf(va).ok_or(PagingError::NotMapped)?;
+        if va & (size.bytes() - 1) != 0 {
+            return Err(PagingError::Unaligned);
+        }
+        let pa = entry.frame().start_address();
+        entry.clear();
+        self.flush(va);
+        Ok((pa, size))
+    }
+
+    // Map [start, end) to [start - offset, end - offset) with the largest pages alignment allows.
+    // Nothing is left mapped on failure.
+    pub fn map_linear(&mut self, start: usize, end: usize, offset: usize, flags: PageTableFlags)
+        -> Result<(), PagingError> {
+        let mut va = start;
+        while va < end {
+            let pa = va.wrapping_sub(offset);
+            let size = PageSize::ALL.iter().cloned()
+                .find(|s| (va | pa) & (s.bytes() - 1) == 0 && s.bytes() <= end - va)
+                .unwrap_or(PageSize::Size4K);
+            if let Err(e) = self.map_huge(VirtAddr::new(va), PhysAddr::new(pa), size, flags) {
+                self.unmap_linear(start, va);
+                return Err(e);
+            }
+            va += size.bytes();
+        }
+        Ok(())
+    }
+
+    // unmap [start, end) mapped by `map_linear`, pages of any size,
+    // holes left by unmapping single pages are skipped
+    pub fn unmap_linear(&mut self, start: usize, end: usize) {
+        let mut va = start;
+        while va < end {
+            va += match self.unmap_huge(VirtAddr::new(va)) {
+                Ok((_, size)) => size.bytes(),
+                Err(_) => PAGE_SIZE
+            };
+        }
+    }
+
+    // the level 0 entry of a page whether it is valid or not
+    pub fn find_entry(&mut self, page: Page) -> Option<&'static mut PageTableEntry> {
+        self.entry(page.start_address().as_usize(), false).ok()
+    }
+
+    // the leaf entry of a page, if it is mapped by a 4KiB page
+    pub fn get_entry(&mut self, page: Page) -> Option<&'static mut PageTableEntry> {
+        let entry = self.entry(page.start_address().as_usize(), false).ok()?;
+        if entry.is_valid() { Some(entry) } else { None }
+    }
+
+    // translate a virtual address, huge pages are taken into account
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let va = addr.as_usize();
+        let (entry, size) = self.leaf(va)?;
+        let pa = entry.frame().start_address().as_usize();
+        Some(PhysAddr::new(pa | (va & (size.bytes() - 1))))
+    }
+
+    // size of the leaf entry mapping `va`
+    pub fn page_size(&self, addr: VirtAddr) -> Option<PageSize> {
+        self.leaf(addr.as_usize()).map(|(_, size)| size)
+    }
+}
+
+impl Drop for PageTable {
+    fn drop(&mut self) {
+        free_table(self.root, LEVELS - 1);
+    }
+}
@@ -0,0 +1,45 @@
+use spin::Mutex;
+
+// A reclaim callback tries to give frames back to the frame allocator
+// and returns how many frames it has released.
+pub type Reclaimer = fn() -> usize;
+
+const MAX_RECLAIMERS: usize = 8;
+
+static RECLAIMERS: Mutex<[Option<Reclaimer>; MAX_RECLAIMERS]> =
+    Mutex::new([None; MAX_RECLAIMERS]);
+
+// register a reclaim callback, return false if the registry is full
+pub fn register(f: Reclaimer) -> bool {
+    let mut reclaimers = RECLAIMERS.lock();
+    for slot in reclaimers.iter_mut() {
+        if slot.is_none() {
+            *slot = Some(f);
+            return true;
+        }
+    }
+    false
+}
+
+pub fn unregister(f: Reclaimer) {
+    let mut reclaimers = RECLAIMERS.lock();
+    for slot in reclaimers.iter_mut() {
+        if *slot == Some(f) {
+            *slot = None;
+        }
+    }
+}
+
+// Run every registered reclaimer and return the total number of frames released.
+// The registry is copied out first so that reclaimers are called without
+// holding any lock and are free to allocate or deallocate frames themselves.
+pub fn run() -> usize {
+    let reclaimers = *RECLAIMERS.lock();
+    let mut released = 0;
+    for f in reclaimers.iter() {
+        if let Some(f) = f {
+            released += f();
+        }
+    }
+    released
+}
@@ -0,0 +1,242 @@
+use core::mem::size_of;
+use core::cmp::max;
+use core::marker::Send;
+use core::ptr::null_mut;
+use crate::consts::KERNEL_HEAP_REGIONS;
+use crate::memory::allocator::{DynamicAllocator, RegionHeap, next_pow_of_2};
+use crate::memory::hybrid_allocator::HeapStats;
+use crate::memory::buddy_allocator::{BuddyAllocator, BuddyStats, BUDDY_ALLOCATOR_GRANULARITY};
+
+// Header at the beginning of a region, the rest of the region is managed by `buddy`
+struct HeapRegion {
+    next: Option<*mut HeapRegion>,
+    start: usize,
+    size: usize,
+    // blocks allocated from this region and not deallocated yet
+    allocated: usize,
+    removable: bool,
+    buddy: BuddyAllocator<'static>
+}
+
+impl HeapRegion {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.start + self.size
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct RegionStats {
+    pub regions: usize,
+    pub size: usize,
+    // free blocks of all the regions together
+    pub free: BuddyStats
+}
+
+// Buddy allocators over a list of memory regions, regions can be added at any time
+pub struct RegionAllocator {
+    regions: Option<*mut HeapRegion>,
+    // the regions again sorted by start address, to find the one a block is from
+    by_start: [*mut HeapRegion; KERNEL_HEAP_REGIONS],
+    region_num: usize
+}
+
+unsafe impl Send for RegionAllocator {}
+
+impl RegionAllocator {
+    pub const fn new() -> Self {
+        RegionAllocator {
+            regions: None,
+            by_start: [null_mut(); KERNEL_HEAP_REGIONS],
+            region_num: 0
+        }
+    }
+    pub fn stats(&self) -> RegionStats {
+        let mut stats = RegionStats::default();
+        let mut region = self.regions;
+        while let Some(p) = region {
+            unsafe {
+                stats.regions += 1;
+                stats.size += (*p).size;
+                stats.free.merge(&(*p).buddy.stats());
+                region = (*p).next;
+            }
+        }
+        stats
+    }
+    // where a region starting at `start` is or would be in `by_start`
+    fn index_of(&self, start: usize) -> Result<usize, usize> {
+        self.by_start[..self.region_num].binary_search_by_key(&start, |&p| unsafe { (*p).start })
+    }
+    fn region_of(&self, addr: usize) -> *mut HeapRegion {
+        // the last region starting at or before `addr`
+        let i = match self.index_of(addr) {
+            Ok(i) => Some(i),
+            Err(i) => i.checked_sub(1)
+        };
+        match i.map(|i| self.by_start[i]) {
+            Some(p) if unsafe { (*p).contains(addr) } => p,
+            _ => panic!("Address 0x{:x} is not in the heap.", addr)
+        }
+    }
+}
+
+impl DynamicAllocator for RegionAllocator {
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut region = self.regions;
+        while let Some(p) = region {
+            unsafe {
+                if let Some(addr) = (*p).buddy.alloc(size, align) {
+                    (*p).allocated += 1;
+                    return Some(addr);
+                }
+                region = (*p).next;
+            }
+        }
+        None
+    }
+    fn dealloc(&mut self, addr: usize, size: usize) {
+        let p = self.region_of(addr);
+        unsafe {
+            (*p).buddy.dealloc(addr, size);
+            (*p).allocated -= 1;
+        }
+    }
+    fn grained(&self, minsz: usize) -> usize {
+        next_pow_of_2(minsz)
+    }
+    fn resize_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        let p = self.region_of(addr);
+        unsafe { (*p).buddy.resize_in_place(addr, size, new_size) }
+    }
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut region = self.regions;
+        while let Some(p) = region {
+            unsafe {
+                if let Some(addr) = (*p).buddy.alloc_zeroed(size, align) {
+                    (*p).allocated += 1;
+                    return Some(addr);
+                }
+                region = (*p).next;
+            }
+        }
+        None
+    }
+}
+
+impl RegionHeap for RegionAllocator {
+    fn add_region(&mut self, start: usize, size: usize, removable: bool, zeroed: bool) -> bool {
+        let header = size_of::<HeapRegion>();
+        assert!(size > header);
+        if self.region_num == KERNEL_HEAP_REGIONS {
+            return false;
+        }
+        let region = start as *mut HeapRegion;
+        unsafe {
+            region.write(HeapRegion {
+                next: None,
+                start,
+                size,
+                allocated: 0,
+                removable,
+                buddy: BuddyAllocator::new()
+            });
+            (*region).buddy.init(start + header, size - header);
+            if zeroed {
+                (*region).buddy.mark_zeroed();
+            }
+        }
+        // regions added later are tried last, so that they are more likely to become unused
+        let mut link = &mut self.regions;
+        while let Some(p) = *link {
+            link = unsafe { &mut (*p).next };
+        }
+        *link = Some(region);
+        let i = self.index_of(start).unwrap_err();
+        self.by_start[self.region_num] = region;
+        self.region_num += 1;
+        self.by_start[i..self.region_num].rotate_right(1);
+        true
+    }
+    fn region_size_for(&self, size: usize, align: usize) -> Option<usize> {
+        // The buddy allocator keeps its bookkeeping at the beginning of the region, the upper half
+        // of a region twice as large as the block is always free if it has a few pages at least.
+        max(max(size, align), 2 * BUDDY_ALLOCATOR_GRANULARITY).checked_next_power_of_two()?.checked_mul(2)
+    }
+    fn take_unused(&mut self) -> Option<(usize, usize)> {
+        let mut link = &mut self.regions;
+        while let Some(p) = *link {
+            unsafe {
+                if (*p).removable && (*p).allocated == 0 {
+                    *link = (*p).next;
+                    let i = self.index_of((*p).start).unwrap();
+                    self.by_start[i..self.region_num].rotate_left(1);
+                    self.region_num -= 1;
+                    return Some(((*p).start, (*p).size));
+                }
+                link = &mut (*p).next;
+            }
+        }
+        None
+    }
+    fn heap_stats(&self) -> HeapStats {
+        HeapStats { back: self.stats(), slub: Default::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::test_util::{Rng, HostMemory, stress};
+
+    #[test]
+    fn stress_two_regions() {
+        let first = HostMemory::new(0x10000, 0x1000);
+        let second = HostMemory::new(0x10000, 0x10000);
+        let mut a = RegionAllocator::new();
+        assert!(a.add_region(first.start(), first.size(), false, true));
+        assert!(a.add_region(second.start(), second.size(), true, false));
+        let lo = core::cmp::min(first.start(), second.start());
+        let hi = core::cmp::max(first.start() + first.size(), second.start() + second.size());
+        stress(&mut a, &mut Rng::new(3), 5000, 0x2000, lo, hi, |_| {});
+        assert_eq!(a.stats().regions, 2);
+        assert_eq!(a.take_unused(), Some((second.start(), second.size())));
+        assert_eq!(a.take_unused(), None);
+        assert_eq!(a.stats().regions, 1);
+    }
+
+    #[test]
+    fn grown_region_fits() {
+        for &size in [1, 100, 0x1000, 0x3000, 0x12345].iter() {
+            for &align in [8, 0x40, 0x1000, 0x10000].iter() {
+                let mut a = RegionAllocator::new();
+                let bytes = a.region_size_for(size, align).unwrap();
+                let memory = HostMemory::new(bytes, bytes);
+                assert!(a.add_region(memory.start(), memory.size(), true, false));
+                let x = a.alloc(size, align).expect("the region is too small");
+                assert_eq!(x % align, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn regions_out_of_order() {
+        const REGION: usize = 0x4000;
+        let memory = HostMemory::new(REGION * (KERNEL_HEAP_REGIONS + 1), 0x1000);
+        let region = |i: usize| memory.start() + i * REGION;
+        let mut a = RegionAllocator::new();
+        // 7 is coprime to the number of regions, so each is added once
+        for i in 0..KERNEL_HEAP_REGIONS {
+            let i = i * 7 % KERNEL_HEAP_REGIONS;
+            assert!(a.add_region(region(i), REGION, i != 0, false));
+        }
+        assert!(!a.add_region(region(KERNEL_HEAP_REGIONS), REGION, true, false));
+        stress(&mut a, &mut Rng::new(5), 5000, 0x1000, region(0), region(KERNEL_HEAP_REGIONS), |_| {});
+        for _ in 1..KERNEL_HEAP_REGIONS {
+            assert!(a.take_unused().is_some());
+        }
+        assert_eq!(a.take_unused(), None);
+        assert_eq!(a.region_of(region(0) + REGION - 1), region(0) as *mut HeapRegion);
+        assert!(a.add_region(region(KERNEL_HEAP_REGIONS), REGION, true, false));
+        assert_eq!(a.region_of(region(KERNEL_HEAP_REGIONS)), region(KERNEL_HEAP_REGIONS) as *mut HeapRegion);
+    }
+}
@@ -1,4 +1,5 @@
 use core::mem::size_of;
+use core::ptr;
 use core::cmp::{min, max};
 use crate::memory::allocator::{
     DynamicAllocator,
@@ -7,9 +8,85 @@ use crate::memory::allocator::{
 };
 
 const slub_pool_sizes: [usize; 15] = 
-    [8, 16, 24, 32, 48, 64, 92, 128, 192, 256, 384, 512, 768, 1024, 2048];
+    [8, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024, 2048];
     //, 4096, 8*1024, 16*1024, 32*1024, 64*1024],
 
+pub const SLUB_POOL_NUM: usize = slub_pool_sizes.len();
+
+// With the `slub-debug` feature every object gets a redzone on both sides. Free objects
+// are poisoned and checked when they are reused, redzones are checked when objects are
+// freed, and the state kept in the left redzone catches double frees.
+#[cfg(feature = "slub-debug")]
+mod debug {
+    use core::mem::size_of;
+    use core::slice;
+
+    pub const REDZONE: usize = 32;
+    const POISON: u8 = 0x6b;
+    const REDZONE_ACTIVE: u8 = 0xbb;
+    const REDZONE_INACTIVE: u8 = 0xcc;
+    // the free list link of a free block is at the beginning of its left redzone
+    const LINK: usize = size_of::<Option<*mut u8>>();
+
+    fn bytes(addr: usize, len: usize) -> &'static mut [u8] {
+        unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) }
+    }
+    fn is(addr: usize, len: usize, pattern: u8) -> bool {
+        bytes(addr, len).iter().all(|&b| b == pattern)
+    }
+    fn fill(addr: usize, len: usize, pattern: u8) {
+        for b in bytes(addr, len).iter_mut() { *b = pattern; }
+    }
+    // make a block which has never been used look freed, but for the free list link
+    pub fn init_free(blk: usize, obj_size: usize) {
+        fill(blk + LINK, REDZONE - LINK, REDZONE_INACTIVE);
+        fill(blk + REDZONE, obj_size, POISON);
+        fill(blk + REDZONE + obj_size, REDZONE, REDZONE_INACTIVE);
+    }
+    pub fn on_alloc(blk: usize, obj_size: usize) {
+        let obj = blk + REDZONE;
+        if !is(blk + LINK, REDZONE - LINK, REDZONE_INACTIVE) || !is(obj + obj_size, REDZONE, REDZONE_INACTIVE) {
+            panic!("Slub: Redzone of free object 0x{:x} is overwritten.", obj);
+        }
+        if !is(obj, obj_size, POISON) {
+            panic!("Slub: Free object 0x{:x} is modified after it was freed.", obj);
+        }
+        fill(blk, REDZONE, REDZONE_ACTIVE);
+        fill(obj + obj_size, REDZONE, REDZONE_ACTIVE);
+    }
+    pub fn on_free(blk: usize, obj_size: usize) {
+        let obj = blk + REDZONE;
+        if is(blk + LINK, REDZONE - LINK, REDZONE_INACTIVE) {
+            panic!("Slub: Double free of object 0x{:x}.", obj);
+        }
+        if !is(blk, REDZONE, REDZONE_ACTIVE) {
+            panic!("Slub: Left redzone of object 0x{:x} is overwritten.", obj);
+        }
+        if !is(obj + obj_size, REDZONE, REDZONE_ACTIVE) {
+            panic!("Slub: Right redzone of object 0x{:x} is overwritten.", obj);
+        }
+        init_free(blk, obj_size);
+    }
+}
+
+#[cfg(not(feature = "slub-debug"))]
+mod debug {
+    pub const REDZONE: usize = 0;
+    pub fn init_free(_blk: usize, _obj_size: usize) {}
+    pub fn on_alloc(_blk: usize, _obj_size: usize) {}
+    pub fn on_free(_blk: usize, _obj_size: usize) {}
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SlubPoolStats {
+    // object size of the size class
+    pub size: usize,
+    pub current_frames: usize,
+    pub partial_frames: usize,
+    pub full_frames: usize,
+    pub objects_in_use: usize
+}
+
 pub struct SlubAllocator<T: DynamicAllocator> {
     slub_pools: [Option<SlubPool<T>>; slub_pool_sizes.len()],
     back_allocator_p: *mut T,
@@ -29,19 +106,21 @@ impl<T: DynamicAllocator> SlubAllocator<T> {
     pub const fn max_size() -> usize {
         slub_pool_sizes[slub_pool_sizes.len() - 1]
     }
+    pub fn stats(&self) -> [SlubPoolStats; SLUB_POOL_NUM] {
+        let mut stats = [SlubPoolStats::default(); SLUB_POOL_NUM];
+        for (s, pool) in stats.iter_mut().zip(self.slub_pools.iter()) {
+            if let Some(ref pool) = pool {
+                *s = pool.stats();
+            }
+        }
+        stats
+    }
     fn pool_id_from_size(&self, minsz: usize) -> usize {
         assert!(minsz <= Self::max_size());
         let mut id = 0;
         while slub_pool_sizes[id] < minsz { id += 1; }
         id
     }
-    fn frame_from_addr(&mut self, addr: usize) -> *mut SlubFrame<T> {
-        let head = unsafe { (*(self.back_allocator_p)).compound_head(addr) };
-        head as *mut SlubFrame<T>
-    }
-    fn pool_from_frame(&mut self, frame: *mut SlubFrame<T>) -> *mut SlubPool<T> {
-        unsafe { (*frame).pool_ptr }
-    }
 }
 
 impl<T: DynamicAllocator> DynamicAllocator for SlubAllocator<T> {
@@ -53,19 +132,14 @@ impl<T: DynamicAllocator> DynamicAllocator for SlubAllocator<T> {
         let pool = self.slub_pools[pool_id].as_mut().unwrap();
         pool.alloc(align)
     }
-    fn dealloc(&mut self, addr: usize) {
-        let frame_p = self.frame_from_addr(addr);
-        if frame_p as usize == addr {
-            // if the frame head equals to addr, 
-            // means this memory block is allocated by
-            // back_allocator.
-            unsafe { (*(self.back_allocator_p)).dealloc(addr) };
+    fn dealloc(&mut self, addr: usize, size: usize) {
+        // the size tells where the block is from, just as in alloc
+        if size > Self::max_size() {
+            unsafe { (*(self.back_allocator_p)).dealloc(addr, size) };
             return;
         }
-        let pool_p = self.pool_from_frame(frame_p);
-        unsafe {
-            (*pool_p).dealloc(addr);
-        }
+        let pool_id = self.pool_id_from_size(size);
+        self.slub_pools[pool_id].as_mut().unwrap().dealloc(addr);
     }
     fn grained(&self, minsz: usize) -> usize { 
         if minsz > Self::max_size() { 
@@ -74,20 +148,27 @@ impl<T: DynamicAllocator> DynamicAllocator for SlubAllocator<T> {
             slub_pool_sizes[self.pool_id_from_size(minsz)]
         }
     }
-    fn compound_head(&mut self, addr: usize) -> usize {
-        let frame_p = self.frame_from_addr(addr);
-        if frame_p as usize == addr {
-            // if the frame head equals to addr, 
-            // means this memory block is allocated by
-            // back_allocator.
-            return frame_p as usize;
+    fn resize_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        // the new size has to lead dealloc to the same place as the old one
+        if size > Self::max_size() {
+            return new_size > Self::max_size() && 
+                unsafe { (*(self.back_allocator_p)).resize_in_place(addr, size, new_size) };
         }
-        let pool_p = self.pool_from_frame(frame_p);
-        unsafe { (*pool_p).compound_head(frame_p as usize, addr) }
+        new_size <= Self::max_size() && self.pool_id_from_size(new_size) == self.pool_id_from_size(size)
+    }
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize> {
+        if size > Self::max_size() { 
+            return unsafe { (*(self.back_allocator_p)).alloc_zeroed(size, align) }; 
+        }
+        // blocks hold the free list links, they are never fresh
+        let addr = self.alloc(size, align)?;
+        unsafe { ptr::write_bytes(addr as *mut u8, 0, size); }
+        Some(addr)
     }
 }
 
-struct SlubPool<T: DynamicAllocator> {
+// The objects of a size class or of a cache, in frames taken from the back allocator
+pub struct SlubPool<T: DynamicAllocator> {
     grained: usize,
     frame_size: usize,
     real_blk_size: usize,
@@ -99,10 +180,16 @@ struct SlubPool<T: DynamicAllocator> {
 }
 
 impl<T: DynamicAllocator> SlubPool<T> {
+    // objects of a size class are aligned to the largest power of 2 dividing the size
     pub fn new(back_allocator_p: *mut T, grained: usize) -> Self {
-        let frame_size = unsafe { (*back_allocator_p).grained(grained*16) };
-        let real_blk_size = max(grained, size_of::<SlubBlk<T>>());
-        let blk_offset = Self::align(size_of::<SlubFrame<T>>(), real_blk_size);
+        Self::with_layout(back_allocator_p, grained, 1 << grained.trailing_zeros())
+    }
+    pub fn with_layout(back_allocator_p: *mut T, grained: usize, align: usize) -> Self {
+        let real_blk_size = Self::align(max(grained, size_of::<SlubBlk<T>>()) + 2 * debug::REDZONE, align);
+        // big enough for the header and a number of blocks
+        let frame_size = unsafe { (*back_allocator_p).grained(real_blk_size*16) };
+        // the object after the left redzone of every block is aligned
+        let blk_offset = Self::align(size_of::<SlubFrame<T>>() + debug::REDZONE, align) - debug::REDZONE;
         SlubPool {
             grained,
             frame_size,
@@ -120,7 +207,10 @@ impl<T: DynamicAllocator> SlubPool<T> {
             unsafe {
                 if !(*frame_p).is_full() {
                     res = unsafe { (*frame_p).alloc(align) };
-                    return res;
+                    return res.map(|blk| {
+                        debug::on_alloc(blk, self.obj_size());
+                        blk + debug::REDZONE
+                    });
                 } else {
                     self.current_frame = None;
                     self.insert_to_full(frame_p);
@@ -144,25 +234,45 @@ impl<T: DynamicAllocator> SlubPool<T> {
     pub fn dealloc(&mut self, addr: usize) {
         let frame_p = (addr & !(self.frame_size - 1)) as *mut SlubFrame<T>;
         unsafe {
-            let is_full = (*frame_p).is_full();
-            (*frame_p).dealloc(addr);
+            // a full current frame is not in the full list
+            let is_full = (*frame_p).is_full() && self.current_frame != Some(frame_p);
+            let blk = addr - debug::REDZONE;
+            debug::on_free(blk, self.obj_size());
+            (*frame_p).dealloc(blk);
             if is_full {
                 self.move_from_full_to_partial(frame_p);
                 return;
             }
             if self.current_frame != Some(frame_p) && (*frame_p).is_empty() {
                 self.drop_from_partial(frame_p);
-                unsafe { (*(self.back_allocator_p)).dealloc(frame_p as usize) };
+                unsafe { (*(self.back_allocator_p)).dealloc(frame_p as usize, self.frame_size) };
             }
         }
     }
+    // Give all the frames back, no object may be in use
+    pub fn release(&mut self) {
+        let mut frames = [self.current_frame.take(), self.partial_frame_list.take(), self.full_frame_list.take()];
+        for frame in frames.iter_mut() {
+            while let Some(frame_p) = *frame {
+                unsafe {
+                    *frame = (*frame_p).next_frame;
+                    (*(self.back_allocator_p)).dealloc(frame_p as usize, self.frame_size);
+                }
+            }
+        }
+    }
+    // bytes of a block left for the object
+    fn obj_size(&self) -> usize {
+        self.real_blk_size - 2 * debug::REDZONE
+    }
     fn align(x: usize, align: usize) -> usize {
         let a = 1 << align.trailing_zeros();
         let mask = a - 1;
         (x + mask) & !mask
     }
     fn alloc_frame(&mut self) -> Option<*mut SlubFrame<T>> {
-        let new_alloc = unsafe { (*(self.back_allocator_p)).alloc(self.frame_size, 1) };
+        // aligned to its size, so that the frame of a block is found by masking its address
+        let new_alloc = unsafe { (*(self.back_allocator_p)).alloc(self.frame_size, self.frame_size) };
         if let Some(new_frame_addr) = new_alloc {
             let new_frame_p = new_frame_addr as *mut SlubFrame<T>;
             unsafe {
@@ -220,11 +330,36 @@ impl<T: DynamicAllocator> SlubPool<T> {
             }
             (*frame_p).next_frame = self.partial_frame_list;
             (*frame_p).last_frame = None;
-            self.full_frame_list = Some(frame_p);
+            self.partial_frame_list = Some(frame_p);
+        }
+    }
+    // number of frames in a list and the objects in use in them
+    fn count(list: Option<*mut SlubFrame<T>>) -> (usize, usize) {
+        let (mut frames, mut in_use) = (0, 0);
+        let mut frame = list;
+        while let Some(frame_p) = frame {
+            unsafe {
+                frames += 1;
+                in_use += (*frame_p).in_use;
+                frame = (*frame_p).next_frame;
+            }
         }
+        (frames, in_use)
     }
-    pub fn compound_head(&mut self, frame_start: usize, addr: usize) -> usize {
-        (addr - frame_start - self.blk_offset) / self.real_blk_size * self.real_blk_size
+    pub fn stats(&self) -> SlubPoolStats {
+        let (current, current_in_use) = match self.current_frame {
+            Some(frame_p) => (1, unsafe { (*frame_p).in_use }),
+            None => (0, 0)
+        };
+        let (partial, partial_in_use) = Self::count(self.partial_frame_list);
+        let (full, full_in_use) = Self::count(self.full_frame_list);
+        SlubPoolStats {
+            size: self.grained,
+            current_frames: current,
+            partial_frames: partial,
+            full_frames: full,
+            objects_in_use: current_in_use + partial_in_use + full_in_use
+        }
     }
 } 
 
@@ -260,11 +395,17 @@ impl<T: DynamicAllocator> SlubFrame<T> {
             // p = (*p).next_blk.unwrap();
             (*p).next_blk = None; 
         }
+        let mut blk = blk_start;
+        while blk <= start + frame_size - real_blk_size {
+            debug::init_free(blk, real_blk_size - 2 * debug::REDZONE);
+            blk += real_blk_size;
+        }
     }
     pub fn alloc(&mut self, align: usize) -> Option<usize> {
         assert!(!self.is_full());
         let mut blk_p = self.free_blks.unwrap();
-        if blk_p as usize % align == 0 {
+        // it is the object after the left redzone that has to be aligned
+        if (blk_p as usize + debug::REDZONE) % align == 0 {
             self.free_blks = unsafe { (*blk_p).next_blk };
             self.in_use += 1;
             return Some(blk_p as usize);
@@ -273,7 +414,7 @@ impl<T: DynamicAllocator> SlubFrame<T> {
         while let Some(blk_p) = blk {
             let next: Option<*mut SlubBlk<T>> = unsafe { (*blk_p).next_blk };
             if let Some(next_p) = next {
-                if next_p as usize % align == 0 {
+                if (next_p as usize + debug::REDZONE) % align == 0 {
                     unsafe { (*blk_p).next_blk = (*next_p).next_blk };
                     self.in_use += 1;
                     return Some(next_p as usize);
@@ -297,3 +438,133 @@ impl<T: DynamicAllocator> SlubFrame<T> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::buddy_allocator::BuddyAllocator;
+    use crate::memory::test_util::{Rng, HostMemory, stress};
+
+    impl<T: DynamicAllocator> SlubPool<T> {
+        // Walk a frame list checking its links, return the frames in it
+        fn check_list(&self, list: Option<*mut SlubFrame<T>>, full: bool) -> usize {
+            let mut frames = 0;
+            let mut last = None;
+            let mut frame = list;
+            while let Some(frame_p) = frame {
+                unsafe {
+                    assert!(Some(frame_p) != self.current_frame, "the current frame is in a list");
+                    assert!((*frame_p).last_frame == last, "broken frame list");
+                    assert!((*frame_p).pool_ptr as *const _ == self as *const _);
+                    assert_eq!((*frame_p).is_full(), full, "frame in the wrong list");
+                    last = Some(frame_p);
+                    frame = (*frame_p).next_frame;
+                }
+                frames += 1;
+            }
+            frames
+        }
+        fn check(&self) {
+            self.check_list(self.partial_frame_list, false);
+            self.check_list(self.full_frame_list, true);
+        }
+    }
+
+    impl<T: DynamicAllocator> SlubAllocator<T> {
+        fn check(&self) {
+            for pool in self.slub_pools.iter() {
+                pool.as_ref().unwrap().check();
+            }
+        }
+    }
+
+    // Run `f` on a slub allocator over a buddy allocator managing `size` bytes
+    fn with_slub<F: FnOnce(&mut SlubAllocator<BuddyAllocator<'static>>, &HostMemory)>(size: usize, f: F) {
+        let memory = HostMemory::new(size, size);
+        let mut back = BuddyAllocator::new();
+        back.init(memory.start(), memory.size());
+        let mut a = SlubAllocator::new(&mut back as *mut BuddyAllocator);
+        f(&mut a, &memory);
+    }
+
+    #[test]
+    fn stress_random() {
+        with_slub(0x400000, |a, memory| {
+            let end = memory.start() + memory.size();
+            stress(a, &mut Rng::new(2), 20000, 0x1000, memory.start(), end, |a| a.check());
+            assert!(a.stats().iter().all(|pool| pool.objects_in_use == 0));
+        });
+    }
+
+    // a full frame which gets a free block goes back to the partial list
+    #[test]
+    fn full_frame_becomes_partial() {
+        with_slub(0x100000, |a, _| {
+            let mut blocks = Vec::new();
+            while a.stats()[0].full_frames < 2 {
+                blocks.push(a.alloc(8, 8).unwrap());
+            }
+            a.dealloc(blocks.remove(0), 8);
+            a.check();
+            let stats = a.stats()[0];
+            assert_eq!((stats.partial_frames, stats.full_frames), (1, 1));
+            for addr in blocks {
+                a.dealloc(addr, 8);
+                a.check();
+            }
+        });
+    }
+
+    // a size padded to its alignment gets a size class aligned enough for it,
+    // or goes to the back allocator when it is larger than all of them
+    #[test]
+    fn padded_sizes_are_aligned() {
+        with_slub(0x400000, |a, _| {
+            for align in (3..13).map(|log2| 1 << log2) {
+                for size in (1..4).map(|n| n * align) {
+                    let addr = a.alloc(size, align).unwrap();
+                    assert_eq!(addr % align, 0, "0x{:x} bytes aligned to 0x{:x}", size, align);
+                    let in_use: usize = a.stats().iter().map(|pool| pool.objects_in_use).sum();
+                    assert_eq!(in_use, if size <= SlubAllocator::<BuddyAllocator>::max_size() { 1 } else { 0 });
+                    a.dealloc(addr, size);
+                }
+            }
+        });
+    }
+
+    #[cfg(feature = "slub-debug")]
+    #[test]
+    #[should_panic(expected = "Double free")]
+    fn double_free_is_detected() {
+        with_slub(0x100000, |a, _| {
+            let addr = a.alloc(32, 8).unwrap();
+            let _keep = a.alloc(32, 8).unwrap();
+            a.dealloc(addr, 32);
+            a.dealloc(addr, 32);
+        });
+    }
+
+    #[cfg(feature = "slub-debug")]
+    #[test]
+    #[should_panic(expected = "Right redzone")]
+    fn overflow_is_detected() {
+        with_slub(0x100000, |a, _| {
+            let addr = a.alloc(32, 8).unwrap();
+            unsafe { *((addr + 32) as *mut u8) = 0; }
+            a.dealloc(addr, 32);
+        });
+    }
+
+    #[cfg(feature = "slub-debug")]
+    #[test]
+    #[should_panic(expected = "modified after it was freed")]
+    fn use_after_free_is_detected() {
+        with_slub(0x100000, |a, _| {
+            let addr = a.alloc(32, 8).unwrap();
+            let _keep = a.alloc(32, 8).unwrap();
+            a.dealloc(addr, 32);
+            unsafe { *(addr as *mut u8) = 0; }
+            a.alloc(32, 8);
+        });
+    }
+}
@@ -0,0 +1,220 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use alloc::collections::BTreeSet;
+use core::ops::Bound::{Excluded, Unbounded};
+use spin::Mutex;
+use riscv::addr::{
+    VirtAddr,
+    Page,
+    Frame
+};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+use crate::consts::PAGE_SIZE;
+use crate::memory::paging::{PageTable, PageTableEntry, PageTableFlags};
+use crate::memory::{
+    alloc_frame,
+    dealloc_frame,
+    frame_ref_count,
+    frame_data,
+    register_reclaimer
+};
+
+const BLOCKS_PER_PAGE: usize = PAGE_SIZE / BLOCK_SIZE;
+// pages evicted each time the frame allocator runs out of memory
+const RECLAIM_BATCH: usize = 16;
+
+// Page sized slots on a block device
+struct SwapSpace {
+    device: Box<dyn BlockDevice>,
+    used: Vec<bool>,
+    free_num: usize
+}
+
+impl SwapSpace {
+    fn new(device: Box<dyn BlockDevice>) -> Self {
+        let slot_num = device.block_num() / BLOCKS_PER_PAGE;
+        let mut used = Vec::new();
+        used.resize(slot_num, false);
+        SwapSpace { device, used, free_num: slot_num }
+    }
+    fn alloc_slot(&mut self) -> Option<usize> {
+        let slot = self.used.iter().position(|&used| !used)?;
+        self.used[slot] = true;
+        self.free_num -= 1;
+        Some(slot)
+    }
+    fn free_slot(&mut self, slot: usize) {
+        assert!(self.used[slot]);
+        self.used[slot] = false;
+        self.free_num += 1;
+    }
+    fn write(&self, slot: usize, data: &[u8; PAGE_SIZE]) {
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            self.device.write_block(slot * BLOCKS_PER_PAGE + i, block);
+        }
+    }
+    fn read(&self, slot: usize, data: &mut [u8; PAGE_SIZE]) {
+        for (i, block) in data.chunks_mut(BLOCK_SIZE).enumerate() {
+            self.device.read_block(slot * BLOCKS_PER_PAGE + i, block);
+        }
+    }
+}
+
+// Pages which may be evicted, as (root frame number of the page table, virtual address),
+// visited in order by the clock hand.
+struct SwapManager {
+    space: SwapSpace,
+    resident: BTreeSet<(usize, usize)>,
+    hand: (usize, usize)
+}
+
+impl SwapManager {
+    fn next_victim(&mut self) -> Option<(usize, usize)> {
+        let next = self.resident.range((Excluded(self.hand), Unbounded)).next()
+            .or_else(|| self.resident.iter().next())
+            .cloned();
+        if let Some(key) = next {
+            self.hand = key;
+        }
+        next
+    }
+}
+
+static SWAP: Mutex<Option<SwapManager>> = Mutex::new(None);
+
+fn page_of(va: usize) -> Page {
+    Page::of_addr(VirtAddr::new(va))
+}
+
+fn key_of(pt: &PageTable, va: usize) -> (usize, usize) {
+    (pt.root_frame().number(), va & !(PAGE_SIZE - 1))
+}
+
+// Swap pages out to `device` when physical memory runs out
+pub fn init(device: Box<dyn BlockDevice>) {
+    let space = SwapSpace::new(device);
+    println!("Swap: 0x{:x} slots available.", space.free_num);
+    *SWAP.lock() = Some(SwapManager {
+        space,
+        resident: BTreeSet::new(),
+        hand: (0, 0)
+    });
+    assert!(register_reclaimer(reclaim), "Swap: No room to register the swap reclaimer.");
+}
+
+// make the page mapped at `va` a candidate for eviction
+pub fn track(pt: &PageTable, va: usize) {
+    if let Some(ref mut manager) = *SWAP.lock() {
+        manager.resident.insert(key_of(pt, va));
+    }
+}
+
+pub fn untrack(pt: &PageTable, va: usize) {
+    if let Some(ref mut manager) = *SWAP.lock() {
+        manager.resident.remove(&key_of(pt, va));
+    }
+}
+
+pub fn is_swapped(pt: &mut PageTable, va: usize) -> bool {
+    match pt.find_entry(page_of(va)) {
+        Some(entry) => !entry.is_valid() && entry.flags().contains(PageTableFlags::SWAPPED),
+        None => false
+    }
+}
+
+// Read a swapped out page back into a new frame, return false if out of memory
+pub fn swap_in(pt: &mut PageTable, va: usize) -> bool {
+    assert!(is_swapped(pt, va));
+    let entry = pt.find_entry(page_of(va)).unwrap();
+    let slot = entry.bits() >> 10;
+    // do not hold the lock, allocating may need to swap other pages out
+    let frame = match alloc_frame() {
+        Some(frame) => frame,
+        None => return false
+    };
+    let mut manager = SWAP.lock();
+    let manager = manager.as_mut().unwrap();
+    manager.space.read(slot, frame_data(frame));
+    manager.space.free_slot(slot);
+    let mut flags = entry.flags();
+    flags.remove(PageTableFlags::SWAPPED);
+    entry.set(frame, flags | PageTableFlags::VALID);
+    // the invalid entry may be cached as well
+    if pt.is_active() {
+        crate::memory::paging::flush_tlb(va);
+    }
+    manager.resident.insert(key_of(pt, va));
+    true
+}
+
+// Release the swap slot of a swapped out page being unmapped,
+// return false if the page is not swapped out
+pub fn free_swapped(pt: &mut PageTable, va: usize) -> bool {
+    if !is_swapped(pt, va) {
+        return false;
+    }
+    let entry = pt.find_entry(page_of(va)).unwrap();
+    SWAP.lock().as_mut().unwrap().space.free_slot(entry.bits() >> 10);
+    entry.clear();
+    true
+}
+
+// Evict up to `n` pages with the clock algorithm and return how many were evicted.
+// Recently accessed pages get a second chance and frames shared copy-on-write are skipped.
+pub fn reclaim_pages(n: usize) -> usize {
+    // allocating inside the swap manager may run out of frames, do not reenter then
+    let mut manager = match SWAP.try_lock() {
+        Some(manager) => manager,
+        None => return 0
+    };
+    let manager = match manager.as_mut() {
+        Some(manager) => manager,
+        None => return 0
+    };
+    let mut released = 0;
+    // every page is visited at most twice, once to clear its accessed bit and once to evict it
+    let mut budget = manager.resident.len() * 2;
+    while released < n && budget > 0 {
+        budget -= 1;
+        let (root, va) = match manager.next_victim() {
+            Some(key) => key,
+            None => break
+        };
+        let mut pt = unsafe { PageTable::from_root(Frame::of_ppn(root)) };
+        let entry = match pt.get_entry(page_of(va)) {
+            Some(entry) => entry,
+            None => {
+                manager.resident.remove(&(root, va));
+                continue;
+            }
+        };
+        let mut flags = entry.flags();
+        if flags.contains(PageTableFlags::ACCESSED) {
+            flags.remove(PageTableFlags::ACCESSED);
+            pt.update_flags(page_of(va), flags).unwrap();
+            continue;
+        }
+        let frame = entry.frame();
+        if frame_ref_count(frame) > 1 {
+            continue;
+        }
+        let slot = match manager.space.alloc_slot() {
+            Some(slot) => slot,
+            None => break
+        };
+        manager.space.write(slot, frame_data(frame));
+        flags.remove(PageTableFlags::VALID);
+        *entry = PageTableEntry::from_bits((slot << 10) | (flags | PageTableFlags::SWAPPED).bits());
+        if pt.is_active() {
+            crate::memory::paging::flush_tlb(va);
+        }
+        manager.resident.remove(&(root, va));
+        dealloc_frame(frame);
+        released += 1;
+    }
+    released
+}
+
+fn reclaim() -> usize {
+    reclaim_pages(RECLAIM_BATCH)
+}
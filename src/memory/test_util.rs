@@ -0,0 +1,122 @@
+// Helpers for the host tests of the allocators
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::collections::BTreeMap;
+use crate::memory::allocator::DynamicAllocator;
+
+// xorshift64*, good enough to shuffle allocations around
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+    // uniform in [l, r)
+    pub fn range(&mut self, l: usize, r: usize) -> usize {
+        l + (self.next() % (r - l) as u64) as usize
+    }
+    pub fn chance(&mut self, percent: usize) -> bool {
+        self.range(0, 100) < percent
+    }
+}
+
+// Memory of the host to be managed by an allocator under test
+pub struct HostMemory {
+    start: usize,
+    layout: Layout
+}
+
+impl HostMemory {
+    pub fn new(size: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let start = unsafe { alloc_zeroed(layout) } as usize;
+        assert!(start != 0);
+        HostMemory { start, layout }
+    }
+    pub fn start(&self) -> usize { self.start }
+    pub fn size(&self) -> usize { self.layout.size() }
+}
+
+impl Drop for HostMemory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.start as *mut u8, self.layout) };
+    }
+}
+
+// A block handed out by the allocator, filled with `tag`
+struct Block {
+    size: usize,
+    tag: u8
+}
+
+fn fill(addr: usize, size: usize, tag: u8) {
+    unsafe { std::ptr::write_bytes(addr as *mut u8, tag, size) };
+}
+
+fn verify(addr: usize, size: usize, tag: u8) {
+    let data = unsafe { std::slice::from_raw_parts(addr as *const u8, size) };
+    assert!(data.iter().all(|&b| b == tag), "block at 0x{:x} was overwritten", addr);
+}
+
+// Randomly allocate, resize and free blocks of at most `max_size` bytes, checking that
+// blocks are aligned, inside [lo, hi), do not overlap and keep their content.
+// `check` verifies the invariants of the allocator after every operation.
+pub fn stress<A, F>(a: &mut A, rng: &mut Rng, rounds: usize, max_size: usize, lo: usize, hi: usize, check: F)
+    where A: DynamicAllocator, F: Fn(&A) {
+    let mut live: BTreeMap<usize, Block> = BTreeMap::new();
+    let mut tag: u8 = 0;
+    for _ in 0..rounds {
+        if live.is_empty() || rng.chance(55) {
+            let size = rng.range(1, max_size + 1);
+            let align = 1 << rng.range(0, 7);
+            let zeroed = rng.chance(20);
+            let res = if zeroed { a.alloc_zeroed(size, align) } else { a.alloc(size, align) };
+            if let Some(addr) = res {
+                assert!(addr % align == 0, "0x{:x} is not aligned to 0x{:x}", addr, align);
+                assert!(addr >= lo && addr + size <= hi, "0x{:x} is out of the managed memory", addr);
+                if let Some((&prev, block)) = live.range(..addr).next_back() {
+                    assert!(prev + block.size <= addr, "0x{:x} overlaps 0x{:x}", addr, prev);
+                }
+                if let Some((&next, _)) = live.range(addr..).next() {
+                    assert!(addr + size <= next, "0x{:x} overlaps 0x{:x}", addr, next);
+                }
+                if zeroed {
+                    verify(addr, size, 0);
+                }
+                tag = tag.wrapping_add(1);
+                fill(addr, size, tag);
+                live.insert(addr, Block { size, tag });
+            }
+        } else {
+            let nth = rng.range(0, live.len());
+            let addr = *live.keys().nth(nth).unwrap();
+            let block = live.remove(&addr).unwrap();
+            verify(addr, block.size, block.tag);
+            if rng.chance(30) {
+                let new_size = rng.range(1, max_size + 1);
+                let next = live.range(addr..).next().map_or(hi, |(&next, _)| next);
+                if a.resize_in_place(addr, block.size, new_size) {
+                    assert!(addr + new_size <= next && addr + new_size <= hi,
+                            "0x{:x} grows over its neighbour", addr);
+                    verify(addr, std::cmp::min(block.size, new_size), block.tag);
+                    fill(addr, new_size, block.tag);
+                    live.insert(addr, Block { size: new_size, tag: block.tag });
+                    check(a);
+                    continue;
+                }
+            }
+            a.dealloc(addr, block.size);
+        }
+        check(a);
+    }
+    for (&addr, block) in live.iter() {
+        verify(addr, block.size, block.tag);
+        a.dealloc(addr, block.size);
+    }
+    check(a);
+}
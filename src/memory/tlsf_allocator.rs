@@ -0,0 +1,503 @@
+use core::mem::size_of;
+use core::ptr::{self, null_mut};
+use core::cmp::{max, min};
+use core::marker::Send;
+use crate::memory::allocator::{DynamicAllocator, RegionHeap};
+use crate::memory::buddy_allocator::{BUDDY_MAX_ORDER, LOG_BUDDY_ALLOCATOR_GRANULARITY};
+use crate::memory::region_allocator::RegionStats;
+use crate::memory::hybrid_allocator::HeapStats;
+
+// Two-Level Segregated Fit: free blocks are kept in lists by size class, the first level
+// splits sizes by powers of two and the second level splits each of them evenly. Bitmaps
+// of the non-empty lists find a large enough block with a few bit operations, so alloc
+// and dealloc take bounded time whatever the state of the heap.
+
+// block sizes and addresses are multiples of the granularity
+const LOG_TLSF_GRANULARITY: usize = 4;
+const TLSF_GRANULARITY: usize = 1 << LOG_TLSF_GRANULARITY;
+const LOG_SL_COUNT: usize = 4;
+const SL_COUNT: usize = 1 << LOG_SL_COUNT;
+// blocks smaller than this are all in the first list of the first level
+const FL_SHIFT: usize = LOG_SL_COUNT + LOG_TLSF_GRANULARITY;
+const SMALL_BLOCK: usize = 1 << FL_SHIFT;
+const FL_COUNT: usize = 40;
+
+struct Block {
+    // the block right before this one in memory, null for the first block of a region
+    prev_phys: *mut Block,
+    // size of the block including the header, the lowest bit is set if it is free
+    size: usize,
+    // links of the free list, only valid while the block is free
+    next_free: *mut Block,
+    prev_free: *mut Block
+}
+
+// the part of a block kept while it is allocated
+const HEADER: usize = 2 * size_of::<usize>();
+const MIN_BLOCK: usize = size_of::<Block>();
+const FREE: usize = 1;
+// set if the block is zero but for its header and free list links, which
+// holds for the blocks cut from a region added zeroed until they are freed
+const FRESH: usize = 2;
+
+unsafe fn block_size(b: *mut Block) -> usize {
+    (*b).size & !(FREE | FRESH)
+}
+
+unsafe fn is_free(b: *mut Block) -> bool {
+    (*b).size & FREE != 0
+}
+
+unsafe fn next_phys(b: *mut Block) -> *mut Block {
+    (b as usize + block_size(b)) as *mut Block
+}
+
+// Cut `b` down to `size` bytes and return the rest as an allocated block of its own
+unsafe fn split(b: *mut Block, size: usize) -> *mut Block {
+    let rest = (b as usize + size) as *mut Block;
+    (*rest).prev_phys = b;
+    (*rest).size = (block_size(b) - size) | ((*b).size & FRESH);
+    (*next_phys(rest)).prev_phys = rest;
+    (*b).size = size | ((*b).size & (FREE | FRESH));
+    rest
+}
+
+fn align_up(x: usize, align: usize) -> usize {
+    (x + align - 1) & !(align - 1)
+}
+
+fn log2(x: usize) -> usize {
+    8 * size_of::<usize>() - 1 - x.leading_zeros() as usize
+}
+
+// the lists blocks of `size` bytes are kept in
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        (0, size >> LOG_TLSF_GRANULARITY)
+    } else {
+        let l = log2(size);
+        (l - FL_SHIFT + 1, (size >> (l - LOG_SL_COUNT)) - SL_COUNT)
+    }
+}
+
+// the first lists in which all the blocks have at least `size` bytes
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK {
+        mapping(size)
+    } else {
+        mapping(size + (1 << (log2(size) - LOG_SL_COUNT)) - 1)
+    }
+}
+
+// bytes of a block holding `size` bytes
+fn block_size_for(size: usize) -> usize {
+    max(align_up(size + HEADER, TLSF_GRANULARITY), MIN_BLOCK)
+}
+
+// Header at the beginning of a region. The blocks follow it, up to a sentinel
+// block which is never free, so that the last block has a neighbour to check.
+struct TlsfRegion {
+    next: Option<*mut TlsfRegion>,
+    start: usize,
+    size: usize,
+    removable: bool,
+    first: *mut Block,
+    sentinel: *mut Block
+}
+
+impl TlsfRegion {
+    // nothing is allocated if the region is a single free block
+    unsafe fn is_unused(&self) -> bool {
+        is_free(self.first) && next_phys(self.first) == self.sentinel
+    }
+}
+
+pub struct TlsfAllocator {
+    // bit i is set if some list of the first level i is not empty
+    fl_bitmap: usize,
+    sl_bitmap: [usize; FL_COUNT],
+    free: [[*mut Block; SL_COUNT]; FL_COUNT],
+    regions: Option<*mut TlsfRegion>
+}
+
+unsafe impl Send for TlsfAllocator {}
+
+impl TlsfAllocator {
+    pub const fn new() -> Self {
+        TlsfAllocator {
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            free: [[null_mut(); SL_COUNT]; FL_COUNT],
+            regions: None
+        }
+    }
+    unsafe fn insert(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping(block_size(b));
+        let head = self.free[fl][sl];
+        (*b).next_free = head;
+        (*b).prev_free = null_mut();
+        if !head.is_null() {
+            (*head).prev_free = b;
+        }
+        self.free[fl][sl] = b;
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+        (*b).size |= FREE;
+    }
+    unsafe fn remove(&mut self, b: *mut Block) {
+        let (fl, sl) = mapping(block_size(b));
+        let (prev, next) = ((*b).prev_free, (*b).next_free);
+        if !next.is_null() {
+            (*next).prev_free = prev;
+        }
+        if !prev.is_null() {
+            (*prev).next_free = next;
+        } else {
+            self.free[fl][sl] = next;
+            if next.is_null() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+        (*b).size &= !FREE;
+    }
+    // a free block in the lists (fl, sl) or in larger ones
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<*mut Block> {
+        if fl >= FL_COUNT {
+            return None;
+        }
+        let mut fl = fl;
+        let mut sl_map = self.sl_bitmap[fl] & (!0 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0 << (fl + 1));
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmap[fl];
+        }
+        Some(self.free[fl][sl_map.trailing_zeros() as usize])
+    }
+    // Free an allocated block, merging it with free neighbours
+    unsafe fn release(&mut self, b: *mut Block) {
+        let mut b = b;
+        let mut size = block_size(b);
+        let next = next_phys(b);
+        if is_free(next) {
+            self.remove(next);
+            size += block_size(next);
+        }
+        let prev = (*b).prev_phys;
+        if !prev.is_null() && is_free(prev) {
+            self.remove(prev);
+            size += block_size(prev);
+            b = prev;
+        }
+        (*b).size = size;
+        (*next_phys(b)).prev_phys = b;
+        self.insert(b);
+    }
+    // Take a block for `size` bytes aligned to `align` out of the lists
+    fn take(&mut self, size: usize, align: usize) -> Option<*mut Block> {
+        let size = block_size_for(size);
+        // a larger block leaves room to move the start to an aligned address
+        let (fl, sl) = if align > TLSF_GRANULARITY {
+            mapping_search(size + align + MIN_BLOCK)
+        } else {
+            mapping_search(size)
+        };
+        let mut b = self.find_suitable(fl, sl)?;
+        unsafe {
+            self.remove(b);
+            if align > TLSF_GRANULARITY {
+                let mut start = align_up(b as usize + HEADER, align) - HEADER;
+                if start != b as usize {
+                    // the part before has to be large enough to become a free block
+                    if start - (b as usize) < MIN_BLOCK {
+                        start = align_up(b as usize + MIN_BLOCK + HEADER, align) - HEADER;
+                    }
+                    let front = b;
+                    b = split(front, start - front as usize);
+                    // its neighbours are allocated, as free blocks are always merged
+                    self.insert(front);
+                }
+            }
+            if block_size(b) >= size + MIN_BLOCK {
+                let rest = split(b, size);
+                self.insert(rest);
+            }
+        }
+        Some(b)
+    }
+    fn stats(&self) -> RegionStats {
+        let mut stats = RegionStats::default();
+        let mut region = self.regions;
+        while let Some(p) = region {
+            unsafe {
+                stats.regions += 1;
+                stats.size += (*p).size;
+                let mut b = (*p).first;
+                while b != (*p).sentinel {
+                    if is_free(b) {
+                        let size = block_size(b) - HEADER;
+                        stats.free.free_bytes += size;
+                        stats.free.largest_free = max(stats.free.largest_free, size);
+                        // counted like the blocks of a buddy allocator, by the largest order they hold
+                        if size >= 1 << LOG_BUDDY_ALLOCATOR_GRANULARITY {
+                            let order = log2(size) - LOG_BUDDY_ALLOCATOR_GRANULARITY;
+                            stats.free.free_blocks[order.min(BUDDY_MAX_ORDER - 1)] += 1;
+                        }
+                    }
+                    b = next_phys(b);
+                }
+                region = (*p).next;
+            }
+        }
+        stats
+    }
+}
+
+impl DynamicAllocator for TlsfAllocator {
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let b = self.take(size, align)?;
+        unsafe { (*b).size &= !FRESH };
+        Some(b as usize + HEADER)
+    }
+    fn dealloc(&mut self, addr: usize, size: usize) {
+        let b = (addr - HEADER) as *mut Block;
+        unsafe {
+            debug_assert!(block_size(b) >= block_size_for(size));
+            if is_free(b) {
+                panic!("Tlsf: Double free of 0x{:x}.", addr);
+            }
+            self.release(b);
+        }
+    }
+    fn grained(&self, minsz: usize) -> usize {
+        block_size_for(minsz) - HEADER
+    }
+    // the header holds the size of the block, `size` is only checked against it
+    fn resize_in_place(&mut self, addr: usize, size: usize, new_size: usize) -> bool {
+        let b = (addr - HEADER) as *mut Block;
+        unsafe { debug_assert!(block_size(b) >= block_size_for(size)) };
+        let size = block_size_for(new_size);
+        unsafe {
+            let next = next_phys(b);
+            if block_size(b) < size {
+                if !is_free(next) || block_size(b) + block_size(next) < size {
+                    return false;
+                }
+                self.remove(next);
+                (*b).size += block_size(next);
+                (*next_phys(b)).prev_phys = b;
+            }
+            if block_size(b) >= size + MIN_BLOCK {
+                let rest = split(b, size);
+                self.release(rest);
+            }
+        }
+        true
+    }
+    fn alloc_zeroed(&mut self, size: usize, align: usize) -> Option<usize> {
+        let b = self.take(size, align)?;
+        let addr = b as usize + HEADER;
+        unsafe {
+            // the links are the only bytes of a fresh block which may not be zero
+            let dirty = if (*b).size & FRESH != 0 { min(size, MIN_BLOCK - HEADER) } else { size };
+            (*b).size &= !FRESH;
+            ptr::write_bytes(addr as *mut u8, 0, dirty);
+        }
+        Some(addr)
+    }
+}
+
+impl RegionHeap for TlsfAllocator {
+    fn add_region(&mut self, start: usize, size: usize, removable: bool, zeroed: bool) -> bool {
+        let first = align_up(start + size_of::<TlsfRegion>(), TLSF_GRANULARITY);
+        let end = (start + size) & !(TLSF_GRANULARITY - 1);
+        assert!(end >= first + MIN_BLOCK + HEADER);
+        assert!(end - first < 1 << (FL_SHIFT + FL_COUNT - 1), "Tlsf: Region is too large.");
+        let region = start as *mut TlsfRegion;
+        unsafe {
+            let first = first as *mut Block;
+            let sentinel = (end - HEADER) as *mut Block;
+            (*first).prev_phys = null_mut();
+            (*first).size = (sentinel as usize - first as usize) | if zeroed { FRESH } else { 0 };
+            (*sentinel).prev_phys = first;
+            (*sentinel).size = 0;
+            self.insert(first);
+            region.write(TlsfRegion { next: None, start, size, removable, first, sentinel });
+        }
+        let mut link = &mut self.regions;
+        while let Some(p) = *link {
+            link = unsafe { &mut (*p).next };
+        }
+        *link = Some(region);
+        true
+    }
+    fn region_size_for(&self, size: usize, align: usize) -> Option<usize> {
+        // the block `alloc` looks for, rounded up to the smallest block of the lists it looks in
+        let block = size.checked_add(align)?.checked_add(MIN_BLOCK + HEADER + TLSF_GRANULARITY)?;
+        let block = block.checked_add(block >> LOG_SL_COUNT)?;
+        // after the region header and before the sentinel
+        block.checked_add(align_up(size_of::<TlsfRegion>(), TLSF_GRANULARITY) + HEADER)
+    }
+    fn take_unused(&mut self) -> Option<(usize, usize)> {
+        let mut link = &mut self.regions;
+        while let Some(p) = *link {
+            unsafe {
+                if (*p).removable && (*p).is_unused() {
+                    *link = (*p).next;
+                    self.remove((*p).first);
+                    return Some(((*p).start, (*p).size));
+                }
+                link = &mut (*p).next;
+            }
+        }
+        None
+    }
+    fn heap_stats(&self) -> HeapStats {
+        HeapStats { back: self.stats(), slub: Default::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::test_util::{Rng, HostMemory, stress};
+
+    impl TlsfAllocator {
+        // The lists hold exactly the free blocks, which are never next to each other
+        fn check(&self) {
+            let mut free_num = 0;
+            let mut region = self.regions;
+            while let Some(p) = region {
+                unsafe {
+                    let mut prev = null_mut();
+                    let mut b = (*p).first;
+                    while b != (*p).sentinel {
+                        assert_eq!((*b).prev_phys, prev);
+                        assert!(block_size(b) >= MIN_BLOCK && block_size(b) % TLSF_GRANULARITY == 0);
+                        if is_free(b) {
+                            assert!(prev.is_null() || !is_free(prev));
+                            free_num += 1;
+                        }
+                        prev = b;
+                        b = next_phys(b);
+                    }
+                    assert_eq!((*b).prev_phys, prev);
+                    region = (*p).next;
+                }
+            }
+            let mut listed = 0;
+            for fl in 0..FL_COUNT {
+                assert_eq!(self.fl_bitmap & (1 << fl) != 0, self.sl_bitmap[fl] != 0);
+                for sl in 0..SL_COUNT {
+                    let mut b = self.free[fl][sl];
+                    assert_eq!(self.sl_bitmap[fl] & (1 << sl) != 0, !b.is_null());
+                    while !b.is_null() {
+                        unsafe {
+                            assert!(is_free(b));
+                            assert_eq!(mapping(block_size(b)), (fl, sl));
+                            b = (*b).next_free;
+                        }
+                        listed += 1;
+                    }
+                }
+            }
+            assert_eq!(listed, free_num);
+        }
+    }
+
+    // the smallest block in the lists (fl, sl)
+    fn list_min(fl: usize, sl: usize) -> usize {
+        if fl == 0 {
+            sl << LOG_TLSF_GRANULARITY
+        } else {
+            let base = 1 << (fl + FL_SHIFT - 1);
+            base + sl * (base >> LOG_SL_COUNT)
+        }
+    }
+
+    #[test]
+    fn size_classes() {
+        for size in (MIN_BLOCK..1 << 20).step_by(TLSF_GRANULARITY) {
+            let (fl, sl) = mapping(size);
+            assert!(fl < FL_COUNT && sl < SL_COUNT);
+            assert!(list_min(fl, sl) <= size);
+            // blocks in the lists found for a size are large enough
+            let (fl, sl) = mapping_search(size);
+            assert!(list_min(fl, sl) >= size);
+        }
+    }
+
+    #[test]
+    fn stress_random() {
+        let memory = HostMemory::new(0x100000, 0x1000);
+        let mut a = TlsfAllocator::new();
+        assert!(a.add_region(memory.start(), memory.size(), false, true));
+        let end = memory.start() + memory.size();
+        stress(&mut a, &mut Rng::new(5), 20000, 0x3000, memory.start(), end, |a| a.check());
+        let header = align_up(size_of::<TlsfRegion>(), TLSF_GRANULARITY);
+        assert_eq!(a.stats().free.free_bytes + header + 2 * HEADER, memory.size());
+    }
+
+    #[test]
+    fn fresh_blocks_are_not_cleared() {
+        let memory = HostMemory::new(0x10000, 0x1000);
+        let mut a = TlsfAllocator::new();
+        // claim the memory is zero while it is not, to see which bytes alloc_zeroed clears
+        unsafe { ptr::write_bytes(memory.start() as *mut u8, 0xff, memory.size()) };
+        assert!(a.add_region(memory.start(), memory.size(), false, true));
+        let x = a.alloc_zeroed(0x100, 8).unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(x as *const u8, 0x100) };
+        assert!(bytes[..MIN_BLOCK - HEADER].iter().all(|&b| b == 0));
+        assert!(bytes[MIN_BLOCK - HEADER..].iter().all(|&b| b == 0xff));
+        // a block is cleared once it has been freed
+        a.dealloc(x, 0x100);
+        let x = a.alloc_zeroed(0x100, 8).unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(x as *const u8, 0x100) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        a.check();
+    }
+
+    #[test]
+    fn grown_region_fits() {
+        for &size in [1, 100, 0x1000, 0x3000, 0x12345].iter() {
+            for &align in [8, 0x40, 0x1000, 0x10000].iter() {
+                let mut a = TlsfAllocator::new();
+                let bytes = align_up(a.region_size_for(size, align).unwrap(), 0x1000);
+                let memory = HostMemory::new(bytes, 0x1000);
+                assert!(a.add_region(memory.start(), memory.size(), true, false));
+                let x = a.alloc(size, align).expect("the region is too small");
+                assert_eq!(x % align, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn unused_region_is_taken() {
+        let first = HostMemory::new(0x10000, 0x1000);
+        let second = HostMemory::new(0x10000, 0x1000);
+        let mut a = TlsfAllocator::new();
+        assert!(a.add_region(first.start(), first.size(), false, false));
+        assert!(a.add_region(second.start(), second.size(), true, false));
+        // too large for both to fit in one region
+        let x = a.alloc(0xc000, 8).unwrap();
+        let y = a.alloc(0xc000, 0x1000).unwrap();
+        assert_eq!(y % 0x1000, 0);
+        a.check();
+        assert_eq!(a.take_unused(), None);
+        let in_second = |addr| addr >= second.start() && addr < second.start() + second.size();
+        assert!(in_second(x) != in_second(y));
+        let (x, y) = if in_second(x) { (x, y) } else { (y, x) };
+        a.dealloc(x, 0xc000);
+        assert_eq!(a.take_unused(), Some((second.start(), second.size())));
+        a.check();
+        a.dealloc(y, 0xc000);
+        assert_eq!(a.stats().regions, 1);
+        a.check();
+    }
+}
@@ -0,0 +1,54 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::consts::{PAGE_SIZE, VMALLOC_START, VMALLOC_END};
+use crate::memory::with_kernel_memory_set;
+use crate::memory::paging::PageTableFlags;
+use crate::memory::memory_set::handler::{ByFrame, Guard};
+
+// Bytes mapped by vmalloc, guard pages are not counted
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+// Allocate `size` bytes of zeroed kernel memory, rounded up to whole pages, which is
+// contiguous in virtual memory but backed by frames from anywhere in physical memory.
+// A guard page is left unmapped below every allocation, so that running over the
+// end of another allocation or the start of this one faults.
+// The pages are only mapped in the kernel address space, so it has to be the
+// active one whenever the memory is used. Zero bytes give None, like too many.
+pub fn vmalloc(size: usize) -> Option<usize> {
+    if size == 0 {
+        return None;
+    }
+    let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    let rw = PageTableFlags::READABLE | PageTableFlags::WRITABLE;
+    let start = with_kernel_memory_set(|ms| {
+        let guard = ms.find_free(VMALLOC_START, VMALLOC_END, size.checked_add(PAGE_SIZE)?)?;
+        let start = guard + PAGE_SIZE;
+        ms.push(guard, start, PageTableFlags::empty(), Guard::new()).ok()?;
+        if ms.push(start, start + size, rw, ByFrame::new()).is_err() {
+            ms.remove(guard).unwrap();
+            return None;
+        }
+        Some(start)
+    })?;
+    USED.fetch_add(size, Ordering::Relaxed);
+    Some(start)
+}
+
+// Unmap memory from vmalloc and give its frames back
+pub fn vfree(addr: usize) {
+    let size = with_kernel_memory_set(|ms| {
+        let size = match ms.find_area(addr) {
+            Some(area) if area.start() == addr && addr >= VMALLOC_START && addr < VMALLOC_END => {
+                area.end() - area.start()
+            }
+            _ => panic!("Memory: 0x{:x} is not allocated by vmalloc.", addr)
+        };
+        ms.remove(addr).unwrap();
+        ms.remove(addr - PAGE_SIZE).unwrap();
+        size
+    });
+    USED.fetch_sub(size, Ordering::Relaxed);
+}
+
+pub fn vmalloc_used() -> usize {
+    USED.load(Ordering::Relaxed)
+}
@@ -61,6 +61,8 @@ pub fn remote_sfence_vma_asid(hart_mask: usize, _start: usize, _size: usize, _as
 }
 
 pub fn shutdown() -> ! {
+    #[cfg(feature = "leak-track")]
+    crate::memory::print_leaks(0);
     sbi_call(SBI_SHUTDOWN, 0, 0, 0);
     unreachable!();
 }
@@ -21,6 +21,21 @@
     bnez sp, trap_from_user
 trap_from_kernel:
     csrr sp, sscratch
+    # check that the trap frame fits above the bottom of the kernel stack,
+    # t0 is parked in sscratch meanwhile
+    csrw sscratch, t0
+    la t0, KERNEL_STACK_BOTTOM
+    ld t0, 0(t0)
+    addi sp, sp, -36 * XLENB
+    bltu sp, t0, kernel_stack_overflow
+    addi sp, sp, 36 * XLENB
+    csrrw t0, sscratch, sp
+    j trap_from_user
+kernel_stack_overflow:
+    # go on with the trap stack, the trap frame still records the overflowed sp
+    addi sp, sp, 36 * XLENB
+    csrrw t0, sscratch, sp
+    la sp, trapstacktop
 trap_from_user:
     # allocate space from stack to save registers
     addi sp, sp, -36 * XLENB
@@ -122,6 +137,8 @@ _to_kernel:
 __alltraps:
     SAVE_ALL
     mv a0, sp
+    # the outermost frame of the trap handler, stack walking stops here
+    mv s0, zero
     jal rust_trap
 
     .global __trapret